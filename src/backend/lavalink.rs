@@ -0,0 +1,1481 @@
+use dashmap::DashMap;
+use lavalink_rs::error::LavalinkError;
+use lavalink_rs::{gateway::*, model::*, LavalinkClient};
+use serenity::async_trait;
+use serenity::http::Http;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use songbird::{ConnectionInfo, Songbird};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::{error, info, warn};
+
+use self::nodes::{least_loaded, parse_addresses, GuildNode, LavalinkNode, NodeHealth};
+use self::queue::QueuedTrack;
+use self::socket::{NodeConfig, NodeEventHandler, TrackException, TrackStuck, VoiceClosed};
+use super::{
+    BackendError, EnqueueOutcome, Enqueued, MusicBackend, NowPlaying, Playback, QueueSnapshot,
+    TrackRequest,
+};
+use crate::commands::music::{loop_mode_suffix, stored_volume, LoopMode, SongQueue};
+use crate::commands::player::{QueueEdit, QueueEdited};
+use crate::redis_store::{GuildSession, PlayArgs, QueuedSong, RedisStore, RedisStoreError};
+use crate::util::{format_duration, time_until};
+
+mod nodes;
+mod queue;
+mod socket;
+
+/// How often the nodes get checked on.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Lavalink sends stats every minute, a node that stays quiet for longer than
+/// this is taken to be gone.
+const NODE_TIMEOUT: Duration = Duration::from_secs(150);
+/// How long a node gets to accept a connection when checked on.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before reconnecting to a node that went down, doubled
+/// after every failed attempt up to the maximum.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// The close code Discord sends when the bot was disconnected from voice.
+const VOICE_DISCONNECTED: u64 = 4014;
+
+/// Why a track stopped before its end.
+#[derive(Debug, Clone, Copy)]
+enum TrackFailure {
+    Exception,
+    Stuck,
+}
+
+impl std::fmt::Display for TrackFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackFailure::Exception => write!(f, "failed to play"),
+            TrackFailure::Stuck => write!(f, "got stuck"),
+        }
+    }
+}
+
+/// What the backend shares with the handlers of the nodes' events and with
+/// the node monitor.
+#[derive(Clone)]
+struct Shared {
+    /// The track each guild plays. Lavalink only ever knows about that one,
+    /// the rest of the queue is kept in Redis like the local player's.
+    current: Arc<DashMap<GuildId, QueuedTrack>>,
+    /// Held while changing a guild's queue, so changes made at the same time
+    /// don't undo each other.
+    queue_locks: Arc<DashMap<GuildId, Arc<Mutex<()>>>>,
+    guild_nodes: Arc<DashMap<GuildId, GuildNode>>,
+    redis_client: redis::Client,
+    http: Arc<Http>,
+}
+
+impl Shared {
+    async fn redis_store(&self) -> Result<RedisStore, RedisStoreError> {
+        Ok(RedisStore::new(
+            self.redis_client.get_async_connection().await?,
+        ))
+    }
+
+    async fn lock_queue(&self, guild_id: GuildId) -> OwnedMutexGuard<()> {
+        let lock = self.queue_locks.entry(guild_id).or_default().clone();
+        lock.lock_owned().await
+    }
+
+    fn current(&self, guild_id: GuildId) -> Option<QueuedTrack> {
+        self.current.get(&guild_id).map(|current| current.clone())
+    }
+
+    fn current_requester(&self, guild_id: GuildId) -> Option<UserId> {
+        self.current.get(&guild_id).map(|current| current.requester)
+    }
+
+    /// The loop mode and whether fair mode is on, kept where the local
+    /// player saves them.
+    async fn settings(&self, guild_id: GuildId) -> Result<(LoopMode, bool), RedisStoreError> {
+        let mut redis_store = self.redis_store().await?;
+        let loop_mode = redis_store
+            .get_loop_mode(guild_id)
+            .await?
+            .and_then(|mode| mode.parse().ok())
+            .unwrap_or_default();
+        let fair_scheduling = redis_store
+            .get_fair_scheduling(guild_id)
+            .await?
+            .unwrap_or_default();
+        Ok((loop_mode, fair_scheduling))
+    }
+
+    /// Meant to be called with the queue locked, along with `save_queue`.
+    async fn load_queue(&self, guild_id: GuildId) -> Result<SongQueue, RedisStoreError> {
+        let songs = self
+            .redis_store()
+            .await?
+            .get_queue(guild_id)
+            .await?
+            .unwrap_or_default();
+        Ok(SongQueue::from(songs))
+    }
+
+    async fn save_queue(
+        &self,
+        guild_id: GuildId,
+        queue: &SongQueue,
+    ) -> Result<(), RedisStoreError> {
+        self.redis_store()
+            .await?
+            .set_queue(guild_id, queue.iter())
+            .await
+    }
+
+    /// Puts a song back at the end of the queue, taking its turn after the
+    /// other requesters in fair mode.
+    async fn requeue(
+        &self,
+        guild_id: GuildId,
+        song: QueuedSong,
+        fair_scheduling: bool,
+    ) -> Result<(), RedisStoreError> {
+        let _lock = self.lock_queue(guild_id).await;
+        if !fair_scheduling {
+            self.redis_store().await?.push_queue(guild_id, song).await?;
+            return Ok(());
+        }
+        let requester = song.requester;
+        let mut queue = self.load_queue(guild_id).await?;
+        queue.push_back(song);
+        queue.interleave_by_requester(Some(requester));
+        self.save_queue(guild_id, &queue).await
+    }
+
+    /// Forgets what the guild plays. The rest of the queue stays in Redis,
+    /// like the local player's does when it leaves.
+    async fn stop_playing(&self, guild_id: GuildId) {
+        let _lock = self.lock_queue(guild_id).await;
+        self.current.remove(&guild_id);
+    }
+
+    /// Plays the track right away, replacing whatever is playing. Tracks are
+    /// started with replace on, so the one they cut off ends as replaced and
+    /// doesn't move the queue on.
+    async fn start(
+        &self,
+        client: &LavalinkClient,
+        guild_id: GuildId,
+        queued: &QueuedTrack,
+    ) -> Result<(), LavalinkError> {
+        self.start_at(client, guild_id, queued, Duration::ZERO)
+            .await
+    }
+
+    async fn start_at(
+        &self,
+        client: &LavalinkClient,
+        guild_id: GuildId,
+        queued: &QueuedTrack,
+        position: Duration,
+    ) -> Result<(), LavalinkError> {
+        // The event loop keeps the position of the node's current track up
+        // to date.
+        if let Some(mut node) = client.nodes().await.get_mut(&guild_id.0) {
+            node.now_playing = Some(TrackQueue {
+                track: queued.track.clone(),
+                start_time: position.as_millis() as u64,
+                end_time: None,
+                requester: Some(queued.requester.into()),
+            });
+        }
+        client
+            .play(guild_id, queued.track.clone())
+            .start_time(position)
+            .replace(true)
+            .start()
+            .await
+    }
+
+    /// Whether the guild plays on the node at `node`. Events from a node the
+    /// guild was moved away from are stale.
+    fn plays_on(&self, guild_id: GuildId, node: usize) -> bool {
+        match self.guild_nodes.get(&guild_id) {
+            Some(guild_node) => guild_node.node == node,
+            None => false,
+        }
+    }
+
+    /// Loads a queued song on the node, by its link if it has one.
+    async fn load(&self, client: &LavalinkClient, song: &QueuedSong) -> Option<QueuedTrack> {
+        let query = match &song.play {
+            PlayArgs::SearchQuery(query) => query,
+            PlayArgs::YoutubeLink(url) => url,
+        };
+        match client.auto_search_tracks(query).await {
+            Ok(tracks) => tracks
+                .tracks
+                .into_iter()
+                .next()
+                .map(|track| QueuedTrack::from_song(track, song)),
+            Err(why) => {
+                error!("Failed to load {}: {}", song.name, why);
+                None
+            }
+        }
+    }
+
+    /// Takes the song after the current one off the queue, dropping `skip`
+    /// songs before it. Forgets the current track once the queue runs out,
+    /// before letting go of the queue, so a song queued meanwhile gets played
+    /// instead of waiting behind nothing.
+    async fn next_song(
+        &self,
+        guild_id: GuildId,
+        skip: usize,
+    ) -> Result<Option<QueuedSong>, RedisStoreError> {
+        let _lock = self.lock_queue(guild_id).await;
+        let songs = self
+            .redis_store()
+            .await?
+            .take_queue(guild_id, skip + 1)
+            .await?;
+        let next = songs.into_iter().nth(skip);
+        if next.is_none() {
+            self.current.remove(&guild_id);
+        }
+        Ok(next)
+    }
+
+    /// Skips `skip` queued songs and plays the one after them, letting the
+    /// channel it was requested from know. Songs that fail to load are
+    /// skipped too. Stops when the queue runs out.
+    async fn play_next(
+        &self,
+        client: &LavalinkClient,
+        guild_id: GuildId,
+        skip: usize,
+    ) -> Result<Option<QueuedTrack>, BackendError> {
+        let mut skip = skip;
+        let next = loop {
+            let song = match self.next_song(guild_id, skip).await? {
+                Some(song) => song,
+                None => break None,
+            };
+            match self.load(client, &song).await {
+                Some(next) => break Some(next),
+                None => {
+                    self.announce(
+                        song.channel_id,
+                        format!("Could not load {}, skipping it", song.name),
+                    )
+                    .await;
+                    skip = 0;
+                }
+            }
+        };
+        let next = match next {
+            Some(next) => next,
+            None => {
+                if let Some(mut node) = client.nodes().await.get_mut(&guild_id.0) {
+                    node.now_playing = None;
+                }
+                client.stop(guild_id).await?;
+                return Ok(None);
+            }
+        };
+
+        self.current.insert(guild_id, next.clone());
+        self.start(client, guild_id, &next).await?;
+        self.announce_next(guild_id, &next).await?;
+        Ok(Some(next))
+    }
+
+    /// Moves on once the current track finished, replaying it or putting it
+    /// back in the queue when looping.
+    async fn finish(&self, client: &LavalinkClient, guild_id: GuildId) -> Result<(), BackendError> {
+        let finished = match self.current(guild_id) {
+            Some(finished) => finished,
+            None => return Ok(()),
+        };
+        let (loop_mode, fair_scheduling) = self.settings(guild_id).await?;
+        match loop_mode {
+            LoopMode::Track => {
+                self.start(client, guild_id, &finished).await?;
+                self.announce_next(guild_id, &finished).await?;
+            }
+            LoopMode::Queue => {
+                self.requeue(guild_id, finished.song(), fair_scheduling)
+                    .await?;
+                self.play_next(client, guild_id, 0).await?;
+            }
+            LoopMode::Off => {
+                self.play_next(client, guild_id, 0).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn announce_next(
+        &self,
+        guild_id: GuildId,
+        next: &QueuedTrack,
+    ) -> Result<(), RedisStoreError> {
+        let queue_len = self.redis_store().await?.queue_len(guild_id).await?;
+        let (loop_mode, _) = self.settings(guild_id).await?;
+        self.announce(
+            next.text_channel_id,
+            format!(
+                "Now playing {} (<{}>), {} tracks in queue{}",
+                next.title(),
+                next.uri(),
+                queue_len,
+                loop_mode_suffix(loop_mode)
+            ),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Deals with the current track failing. Tries another search result for
+    /// it once, otherwise moves on to the next track.
+    async fn recover(&self, client: &LavalinkClient, guild_id: GuildId, failure: TrackFailure) {
+        let failed = match self.current(guild_id) {
+            Some(failed) => failed,
+            None => return,
+        };
+        error!(
+            guild_id = guild_id.0,
+            track = failed.title(),
+            uri = failed.uri(),
+            requester = failed.requester.0,
+            retried = failed.retried,
+            "Track {}",
+            failure
+        );
+
+        if !failed.retried {
+            if let Some(alternative) = self.alternative(client, &failed).await {
+                self.current.insert(guild_id, alternative.clone());
+                match self.start(client, guild_id, &alternative).await {
+                    Ok(()) => {
+                        self.announce(
+                            failed.text_channel_id,
+                            format!(
+                                "{} {}, trying {} (<{}>) instead",
+                                failed.title(),
+                                failure,
+                                alternative.title(),
+                                alternative.uri()
+                            ),
+                        )
+                        .await;
+                        return;
+                    }
+                    Err(why) => error!("Failed to play the alternative in {}: {}", guild_id, why),
+                }
+            }
+        }
+
+        self.announce(
+            failed.text_channel_id,
+            format!("{} {}, skipping it", failed.title(), failure),
+        )
+        .await;
+        if let Err(why) = self.play_next(client, guild_id, 0).await {
+            error!("Failed to play the next track in {}: {}", guild_id, why);
+        }
+    }
+
+    /// Searches for another track to play in place of one that failed.
+    async fn alternative(
+        &self,
+        client: &LavalinkClient,
+        failed: &QueuedTrack,
+    ) -> Option<QueuedTrack> {
+        let failed_id = failed.track.info.as_ref().map(|info| &info.identifier);
+        let tracks = match client.search_tracks(&failed.search).await {
+            Ok(tracks) => tracks.tracks,
+            Err(why) => {
+                error!("Failed to search for an alternative: {}", why);
+                return None;
+            }
+        };
+        let track = tracks
+            .into_iter()
+            .find(|track| track.info.as_ref().map(|info| &info.identifier) != failed_id)?;
+        Some(QueuedTrack {
+            track,
+            retried: true,
+            ..failed.clone()
+        })
+    }
+
+    async fn announce(&self, channel_id: ChannelId, message: String) {
+        if let Err(why) = channel_id.say(&self.http, message).await {
+            error!("Error sending message: {:?}", why);
+        }
+    }
+}
+
+/// Handles the events of one node. Tracks that throw or get stuck are
+/// recovered from on Lavalink's own exception and stuck events.
+struct LavalinkHandler {
+    node: usize,
+    health: Arc<NodeHealth>,
+    shared: Shared,
+}
+
+#[async_trait]
+impl LavalinkEventHandler for LavalinkHandler {
+    async fn stats(&self, _client: LavalinkClient, event: Stats) {
+        self.health.update(&event);
+    }
+
+    async fn track_start(&self, _client: LavalinkClient, event: TrackStart) {
+        self.health.seen();
+        info!("Track started!\nGuild: {}", event.guild_id);
+    }
+
+    async fn player_update(&self, _client: LavalinkClient, _event: PlayerUpdate) {
+        self.health.seen();
+    }
+
+    async fn track_finish(&self, client: LavalinkClient, event: TrackFinish) {
+        self.health.seen();
+        info!(
+            "Track finished!\nGuild: {}\nReason: {}",
+            event.guild_id, event.reason
+        );
+
+        // Only a track that ended on its own moves the queue on. Replaced and
+        // stopped ones were cut off by a skip or a leave, which already took
+        // care of what comes next, and one that failed to load got an
+        // exception event first.
+        if event.reason != "FINISHED" {
+            return;
+        }
+        let guild_id = GuildId(event.guild_id);
+        if !self.is_current(guild_id, &event.track) {
+            return;
+        }
+        if let Err(why) = self.shared.finish(&client, guild_id).await {
+            error!("Failed to play the next track in {}: {}", guild_id, why);
+        }
+    }
+}
+
+#[async_trait]
+impl NodeEventHandler for LavalinkHandler {
+    async fn track_exception(&self, client: LavalinkClient, event: TrackException) {
+        self.health.seen();
+        let guild_id = GuildId(event.guild_id);
+        warn!("Track failed in {}: {}", guild_id, event.message);
+        if self.is_current(guild_id, &event.track) {
+            self.shared
+                .recover(&client, guild_id, TrackFailure::Exception)
+                .await;
+        }
+    }
+
+    async fn track_stuck(&self, client: LavalinkClient, event: TrackStuck) {
+        self.health.seen();
+        let guild_id = GuildId(event.guild_id);
+        warn!("Track stuck in {} for over {:?}", guild_id, event.threshold);
+        if self.is_current(guild_id, &event.track) {
+            self.shared
+                .recover(&client, guild_id, TrackFailure::Stuck)
+                .await;
+        }
+    }
+
+    async fn voice_closed(&self, client: LavalinkClient, event: VoiceClosed) {
+        self.health.seen();
+        let guild_id = GuildId(event.guild_id);
+        warn!(
+            "Voice connection closed in {}: {} {} (by Discord: {})",
+            guild_id, event.code, event.reason, event.by_remote
+        );
+        if event.code != VOICE_DISCONNECTED || !self.shared.plays_on(guild_id, self.node) {
+            return;
+        }
+        // The bot was kicked from the channel or the channel is gone, the
+        // player has nothing left to play to.
+        self.shared.guild_nodes.remove(&guild_id);
+        self.shared.stop_playing(guild_id).await;
+        if let Err(why) = client.destroy(guild_id).await {
+            error!("Failed to destroy the player of {}: {}", guild_id, why);
+        }
+        client.nodes().await.remove(&guild_id.0);
+    }
+}
+
+impl LavalinkHandler {
+    /// Whether the event is about the track the guild plays on this node.
+    /// Events from a node the guild moved away from are stale.
+    fn is_current(&self, guild_id: GuildId, track: &str) -> bool {
+        if !self.shared.plays_on(guild_id, self.node) {
+            return false;
+        }
+        match self.shared.current.get(&guild_id) {
+            Some(current) => current.track.track == track,
+            None => false,
+        }
+    }
+}
+
+/// Plays on Lavalink nodes. Songbird only joins the voice channel and hands
+/// the connection over to a node, the least loaded one when the guild starts
+/// playing. Lavalink only plays one track at a time, the queue is kept in
+/// Redis the same way the local player keeps it.
+pub struct LavalinkBackend {
+    nodes: Arc<Vec<LavalinkNode>>,
+    manager: Arc<Songbird>,
+    shared: Shared,
+}
+
+impl LavalinkBackend {
+    /// Connects to the nodes listed in `LAVALINK_NODES` as comma separated
+    /// `host:port` addresses, or the one at `LAVALINK_HOST` and
+    /// `LAVALINK_PORT`, all using `LAVALINK_PASSWORD`. Nodes that can't be
+    /// reached are left out, as long as one can.
+    pub async fn from_env(
+        bot_id: UserId,
+        manager: Arc<Songbird>,
+        redis_client: redis::Client,
+        http: Arc<Http>,
+    ) -> Result<Self, BackendError> {
+        let addresses = match env::var("LAVALINK_NODES") {
+            Ok(addresses) => parse_addresses(&addresses),
+            Err(_) => {
+                let port: u16 = env::var("LAVALINK_PORT")
+                    .expect("LAVALINK_PORT")
+                    .parse()
+                    .expect("LAVALINK_PORT must be a port number");
+                let host = env::var("LAVALINK_HOST").expect("LAVALINK_HOST");
+                vec![(host, port)]
+            }
+        };
+        assert!(
+            !addresses.is_empty(),
+            "LAVALINK_NODES doesn't list any node"
+        );
+        let password = env::var("LAVALINK_PASSWORD").expect("LAVALINK_PASSWORD");
+
+        let shared = Shared {
+            current: Arc::new(DashMap::new()),
+            queue_locks: Arc::new(DashMap::new()),
+            guild_nodes: Arc::new(DashMap::new()),
+            redis_client,
+            http,
+        };
+        let mut nodes = Vec::new();
+        let mut last_error = None;
+        for (host, port) in addresses {
+            let config = NodeConfig {
+                host,
+                port,
+                password: password.clone(),
+                bot_id,
+            };
+            let health = Arc::new(NodeHealth::default());
+            let handler = Arc::new(LavalinkHandler {
+                node: nodes.len(),
+                health: health.clone(),
+                shared: shared.clone(),
+            });
+            match socket::connect(&config, None, handler, health.clone()).await {
+                Ok((client, _)) => {
+                    info!("Connected to Lavalink node {}", config.address());
+                    nodes.push(LavalinkNode::new(config, client, health));
+                }
+                Err(why) => {
+                    error!(
+                        "Failed to connect to Lavalink node {}: {}",
+                        config.address(),
+                        why
+                    );
+                    last_error = Some(why);
+                }
+            }
+        }
+        if let Some(why) = last_error {
+            if nodes.is_empty() {
+                return Err(why.into());
+            }
+        }
+
+        let nodes = Arc::new(nodes);
+        tokio::spawn(monitor_nodes(
+            nodes.clone(),
+            shared.clone(),
+            manager.clone(),
+        ));
+        Ok(LavalinkBackend {
+            nodes,
+            manager,
+            shared,
+        })
+    }
+
+    /// The client of the node the guild plays on.
+    fn client(&self, guild_id: GuildId) -> Option<LavalinkClient> {
+        let node = self.shared.guild_nodes.get(&guild_id)?.node;
+        Some(self.nodes[node].client())
+    }
+
+    fn client_or_reject(
+        &self,
+        guild_id: GuildId,
+        why: &str,
+    ) -> Result<LavalinkClient, BackendError> {
+        self.client(guild_id)
+            .ok_or_else(|| BackendError::Rejected(why.to_string()))
+    }
+
+    /// Rejoins the voice channel of a session saved before a restart and
+    /// picks its track up where it stopped.
+    async fn restore_session(
+        &self,
+        guild_id: GuildId,
+        session: &GuildSession,
+    ) -> Result<(), BackendError> {
+        // The voice connection of the last run died with it, so this joins
+        // afresh rather than reusing it.
+        self.join(guild_id, session.voice_channel_id).await?;
+        let client = self.client_or_reject(guild_id, "Not in a voice channel")?;
+        let track = match self.shared.load(&client, &session.song).await {
+            Some(track) => track,
+            None => {
+                return Err(BackendError::Rejected(format!(
+                    "Could not load {}",
+                    session.song.name
+                )))
+            }
+        };
+        let volume = stored_volume(&self.shared.redis_client, guild_id).await;
+        if let Err(why) = client.volume(guild_id, volume).await {
+            error!("Failed to set volume: {}", why);
+        }
+
+        {
+            let _lock = self.shared.lock_queue(guild_id).await;
+            self.shared.current.insert(guild_id, track.clone());
+        }
+        if let Err(why) = self
+            .shared
+            .start_at(&client, guild_id, &track, session.position)
+            .await
+        {
+            self.shared.current.remove(&guild_id);
+            return Err(why.into());
+        }
+        if session.paused {
+            client.set_pause(guild_id, true).await?;
+            if let Some(mut node) = client.nodes().await.get_mut(&guild_id.0) {
+                node.is_paused = true;
+            }
+        }
+        self.shared
+            .announce(
+                session.text_channel_id,
+                format!(
+                    "Back after a restart, {} {} at {}",
+                    if session.paused { "paused" } else { "resuming" },
+                    session.song.name,
+                    format_duration(session.position)
+                ),
+            )
+            .await;
+        Ok(())
+    }
+
+    /// Saves what the guild plays and where, tells the channel and leaves
+    /// voice. The queue stays in Redis.
+    async fn suspend_guild(&self, guild_id: GuildId) -> Result<(), BackendError> {
+        let current = self.shared.current(guild_id);
+        let voice_channel_id = match self.manager.get(guild_id) {
+            Some(call) => call
+                .lock()
+                .await
+                .current_channel()
+                .map(|channel_id| ChannelId(channel_id.0)),
+            None => None,
+        };
+        if let (Some(current), Some(voice_channel_id)) = (current, voice_channel_id) {
+            let (position, paused) = match self.client(guild_id) {
+                Some(client) => last_position(&client, guild_id).await,
+                None => (Duration::ZERO, false),
+            };
+            let session = GuildSession {
+                voice_channel_id,
+                text_channel_id: current.text_channel_id,
+                position,
+                paused,
+                song: current.song(),
+            };
+            self.shared
+                .redis_store()
+                .await?
+                .set_session(guild_id, &session)
+                .await?;
+            self.shared
+                .announce(
+                    current.text_channel_id,
+                    "Restarting, back soon to pick up where we left off".to_string(),
+                )
+                .await;
+        }
+        self.leave(guild_id).await
+    }
+}
+
+/// Checks on the nodes every `HEALTH_CHECK_INTERVAL`. A node counts as gone
+/// once its connection closed, it can't be reached or it has been quiet for
+/// too long. Its guilds move to another node if there is one, and it gets
+/// reconnected to in the background.
+async fn monitor_nodes(nodes: Arc<Vec<LavalinkNode>>, shared: Shared, manager: Arc<Songbird>) {
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (index, node) in nodes.iter().enumerate() {
+            if node.health.is_down() {
+                continue;
+            }
+            let reachable = node.health.is_connected()
+                && matches!(
+                    tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(node.address())).await,
+                    Ok(Ok(_))
+                );
+            if reachable && node.health.silent_for() < NODE_TIMEOUT {
+                continue;
+            }
+            warn!("Lavalink node {} is down", node.address());
+            node.health.mark_down();
+            failover(&nodes, &shared, index).await;
+            tokio::spawn(reconnect(
+                nodes.clone(),
+                index,
+                shared.clone(),
+                manager.clone(),
+            ));
+        }
+    }
+}
+
+/// Moves every guild playing on the node at `from` to the least loaded node
+/// left, picking the current track up where the last player update had it.
+/// With no other node up, the guilds wait for this one to come back.
+async fn failover(nodes: &[LavalinkNode], shared: &Shared, from: usize) {
+    let guilds: Vec<(GuildId, GuildNode)> = shared
+        .guild_nodes
+        .iter()
+        .filter(|entry| entry.node == from)
+        .map(|entry| (*entry.key(), entry.value().clone()))
+        .collect();
+    let old_client = nodes[from].client();
+
+    for (guild_id, guild_node) in guilds {
+        let to = match least_loaded(nodes) {
+            Some(to) => to,
+            None => {
+                warn!(
+                    "No other Lavalink node is up, waiting for {} to come back",
+                    nodes[from].address()
+                );
+                return;
+            }
+        };
+        let (position, paused) = last_position(&old_client, guild_id).await;
+        let client = nodes[to].client();
+        match reattach(
+            &client,
+            to,
+            shared,
+            guild_id,
+            guild_node.connection,
+            position,
+            paused,
+        )
+        .await
+        {
+            Ok(()) => info!(
+                "Moved {} from Lavalink node {} to {}",
+                guild_id,
+                nodes[from].address(),
+                nodes[to].address()
+            ),
+            Err(why) => error!(
+                "Failed to move {} to Lavalink node {}: {}",
+                guild_id,
+                nodes[to].address(),
+                why
+            ),
+        }
+    }
+}
+
+/// Reconnects to the node at `index`, waiting twice as long after every
+/// failed attempt, then sorts out the players it had.
+async fn reconnect(
+    nodes: Arc<Vec<LavalinkNode>>,
+    index: usize,
+    shared: Shared,
+    manager: Arc<Songbird>,
+) {
+    let node = &nodes[index];
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    let resumed = loop {
+        tokio::time::sleep(backoff).await;
+        let handler = Arc::new(LavalinkHandler {
+            node: index,
+            health: node.health.clone(),
+            shared: shared.clone(),
+        });
+        let previous = node.client();
+        match socket::connect(&node.config, Some(&previous), handler, node.health.clone()).await {
+            Ok((client, resumed)) => {
+                node.set_client(client);
+                break resumed;
+            }
+            Err(why) => {
+                backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                warn!(
+                    "Failed to reconnect to Lavalink node {}, trying again in {:?}: {}",
+                    node.address(),
+                    backoff,
+                    why
+                );
+            }
+        }
+    };
+    info!(
+        "Reconnected to Lavalink node {}, session resumed: {}",
+        node.address(),
+        resumed
+    );
+    restore_players(&nodes, index, &shared, &manager, resumed).await;
+}
+
+/// Sorts out the players of a node that's back. Those of guilds that moved to
+/// another node in the meantime go. Unless Lavalink resumed the session, the
+/// others are gone too, and get created again with the voice connection they
+/// had for the guilds songbird is still connected in.
+async fn restore_players(
+    nodes: &[LavalinkNode],
+    index: usize,
+    shared: &Shared,
+    manager: &Songbird,
+    resumed: bool,
+) {
+    let client = nodes[index].client();
+    let players = client.nodes().await;
+    let guild_ids: Vec<GuildId> = players.iter().map(|entry| GuildId(*entry.key())).collect();
+
+    for guild_id in guild_ids {
+        if !shared.plays_on(guild_id, index) {
+            if resumed {
+                if let Err(why) = client.destroy(guild_id).await {
+                    error!("Failed to destroy the player of {}: {}", guild_id, why);
+                }
+            }
+            players.remove(&guild_id.0);
+            continue;
+        }
+        if resumed {
+            continue;
+        }
+
+        let connected = match manager.get(guild_id) {
+            Some(call) => call.lock().await.current_channel().is_some(),
+            None => false,
+        };
+        let connection = shared
+            .guild_nodes
+            .get(&guild_id)
+            .map(|guild_node| guild_node.connection.clone());
+        let connection = match connection {
+            Some(connection) if connected => connection,
+            _ => {
+                shared.guild_nodes.remove(&guild_id);
+                shared.stop_playing(guild_id).await;
+                players.remove(&guild_id.0);
+                continue;
+            }
+        };
+        let (position, paused) = last_position(&client, guild_id).await;
+        // The bot never left the channel, so the voice connection it joined
+        // with still holds for the new player.
+        if let Err(why) = reattach(
+            &client, index, shared, guild_id, connection, position, paused,
+        )
+        .await
+        {
+            error!("Failed to recreate the player of {}: {}", guild_id, why);
+        }
+    }
+}
+
+/// Where the node last had the guild's track, and whether it was paused.
+async fn last_position(client: &LavalinkClient, guild_id: GuildId) -> (Duration, bool) {
+    match client.nodes().await.get(&guild_id.0) {
+        Some(node) => (
+            node.now_playing
+                .as_ref()
+                .and_then(|playing| playing.track.info.as_ref())
+                .map(|info| Duration::from_millis(info.position))
+                .unwrap_or_default(),
+            node.is_paused,
+        ),
+        None => (Duration::ZERO, false),
+    }
+}
+
+/// Sets the guild up on the node at `node` with the given voice connection
+/// and picks its current track up at `position`.
+async fn reattach(
+    client: &LavalinkClient,
+    node: usize,
+    shared: &Shared,
+    guild_id: GuildId,
+    connection: ConnectionInfo,
+    position: Duration,
+    paused: bool,
+) -> Result<(), LavalinkError> {
+    client.create_session(&connection).await?;
+    shared
+        .guild_nodes
+        .insert(guild_id, GuildNode { node, connection });
+    let volume = stored_volume(&shared.redis_client, guild_id).await;
+    if let Err(why) = client.volume(guild_id, volume).await {
+        error!("Failed to set volume: {}", why);
+    }
+
+    if let Some(current) = shared.current(guild_id) {
+        shared
+            .start_at(client, guild_id, &current, position)
+            .await?;
+        if paused {
+            client.set_pause(guild_id, true).await?;
+            if let Some(mut node) = client.nodes().await.get_mut(&guild_id.0) {
+                node.is_paused = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl MusicBackend for LavalinkBackend {
+    fn name(&self) -> &'static str {
+        "lavalink"
+    }
+
+    async fn player_count(&self) -> usize {
+        self.shared.guild_nodes.len()
+    }
+
+    async fn join(&self, guild_id: GuildId, channel_id: ChannelId) -> Result<(), BackendError> {
+        // Stay on the node the guild already plays on, if it's still there.
+        let current = self
+            .shared
+            .guild_nodes
+            .get(&guild_id)
+            .map(|guild_node| guild_node.node)
+            .filter(|&node| !self.nodes[node].health.is_down());
+        let node = match current.or_else(|| least_loaded(&self.nodes)) {
+            Some(node) => node,
+            None => {
+                return Err(BackendError::Rejected(
+                    "No Lavalink node is available right now".to_string(),
+                ))
+            }
+        };
+
+        let (_, handler) = self.manager.join_gateway(guild_id, channel_id).await;
+        let connection = handler?;
+        self.nodes[node]
+            .client()
+            .create_session(&connection)
+            .await?;
+        self.shared
+            .guild_nodes
+            .insert(guild_id, GuildNode { node, connection });
+        Ok(())
+    }
+
+    async fn leave(&self, guild_id: GuildId) -> Result<(), BackendError> {
+        if self.manager.get(guild_id).is_none() {
+            return Err(BackendError::Rejected("Not in a voice channel".to_string()));
+        }
+        self.shared.stop_playing(guild_id).await;
+        self.manager.remove(guild_id).await?;
+        if let Some((_, guild_node)) = self.shared.guild_nodes.remove(&guild_id) {
+            let client = self.nodes[guild_node.node].client();
+            client.destroy(guild_id).await?;
+            client.nodes().await.remove(&guild_id.0);
+        }
+        Ok(())
+    }
+
+    async fn enqueue(
+        &self,
+        guild_id: GuildId,
+        request: TrackRequest,
+    ) -> Result<EnqueueOutcome, BackendError> {
+        let has_session = match self.client(guild_id) {
+            Some(client) => client.nodes().await.contains_key(&guild_id.0),
+            None => false,
+        };
+        if self.manager.get(guild_id).is_none() || !has_session {
+            self.join(guild_id, request.voice_channel_id).await?;
+        }
+        let client = self.client_or_reject(guild_id, "Not in a voice channel")?;
+
+        let query = match &request.play {
+            PlayArgs::SearchQuery(query) => query,
+            PlayArgs::YoutubeLink(url) => url,
+        };
+        let track = match client.auto_search_tracks(query).await?.tracks.first() {
+            Some(track) => track.clone(),
+            None => {
+                return Err(BackendError::Rejected(
+                    "Could not find any video of the search query.".to_string(),
+                ))
+            }
+        };
+        // A link can't be searched again, its title can.
+        let search = match &request.play {
+            PlayArgs::SearchQuery(query) => query.clone(),
+            PlayArgs::YoutubeLink(_) => track
+                .info
+                .as_ref()
+                .map(|info| info.title.clone())
+                .unwrap_or_default(),
+        };
+        let queued = QueuedTrack {
+            track,
+            requester: request.requester,
+            text_channel_id: request.text_channel_id,
+            search,
+            retried: false,
+        };
+
+        let volume = stored_volume(&self.shared.redis_client, guild_id).await;
+        if let Err(why) = client.volume(guild_id, volume).await {
+            error!("Failed to set volume: {}", why);
+        }
+
+        let (loop_mode, fair_scheduling) = self.shared.settings(guild_id).await?;
+        let index = {
+            let _lock = self.shared.lock_queue(guild_id).await;
+            if !self.shared.current.contains_key(&guild_id) {
+                self.shared.current.insert(guild_id, queued.clone());
+                None
+            } else if request.next {
+                self.shared
+                    .redis_store()
+                    .await?
+                    .push_queue_front(guild_id, queued.song())
+                    .await?;
+                Some(0)
+            } else if fair_scheduling {
+                let mut queue = self.shared.load_queue(guild_id).await?;
+                queue.push_back(queued.song());
+                queue.interleave_by_requester(self.shared.current_requester(guild_id));
+                self.shared.save_queue(guild_id, &queue).await?;
+                // Interleaving keeps each requester's songs in order, so the
+                // new one is still the last of theirs.
+                Some(
+                    queue
+                        .iter()
+                        .rposition(|song| song.requester == queued.requester)
+                        .unwrap_or_default(),
+                )
+            } else {
+                let len = self
+                    .shared
+                    .redis_store()
+                    .await?
+                    .push_queue(guild_id, queued.song())
+                    .await?;
+                Some(len - 1)
+            }
+        };
+        let index = match index {
+            Some(index) => index,
+            None => {
+                if let Err(why) = self.shared.start(&client, guild_id, &queued).await {
+                    self.shared.current.remove(&guild_id);
+                    return Err(why.into());
+                }
+                self.shared
+                    .announce(
+                        request.text_channel_id,
+                        format!("Playing {} (<{}>)", queued.title(), queued.uri()),
+                    )
+                    .await;
+                return Ok(EnqueueOutcome::Started);
+            }
+        };
+
+        let elapsed = self.position(guild_id).await?.unwrap_or_default();
+        let songs = self
+            .shared
+            .redis_store()
+            .await?
+            .get_queue(guild_id)
+            .await?
+            .unwrap_or_default();
+        let current = self.shared.current(guild_id);
+        Ok(EnqueueOutcome::Queued {
+            name: queued.title().to_string(),
+            queued: Enqueued {
+                position: index,
+                queue_len: songs.len(),
+                // Looping a single track means the queue never moves on.
+                starts_in: match loop_mode {
+                    LoopMode::Track => None,
+                    _ => Some(time_until(
+                        current.map(|current| current.song()).as_ref(),
+                        elapsed,
+                        &songs,
+                        index,
+                    )),
+                },
+            },
+        })
+    }
+
+    async fn skip(&self, guild_id: GuildId, count: usize) -> Result<usize, BackendError> {
+        if !self.shared.current.contains_key(&guild_id) {
+            return Err(BackendError::Rejected("Nothing to skip".to_string()));
+        }
+        let client = self.client_or_reject(guild_id, "Nothing to skip")?;
+
+        let (loop_mode, _) = self.shared.settings(guild_id).await?;
+        let skip = if loop_mode == LoopMode::Queue {
+            // The skipped songs go back to the end of the queue instead.
+            let _lock = self.shared.lock_queue(guild_id).await;
+            let mut queue = self.shared.load_queue(guild_id).await?;
+            if let Some(current) = self.shared.current(guild_id) {
+                queue.push_back(current.song());
+            }
+            for _ in 1..count {
+                if let Some(song) = queue.pop_front() {
+                    queue.push_back(song);
+                }
+            }
+            self.shared.save_queue(guild_id, &queue).await?;
+            0
+        } else {
+            count.saturating_sub(1)
+        };
+        match self.shared.play_next(&client, guild_id, skip).await? {
+            Some(_) => Ok(self.shared.redis_store().await?.queue_len(guild_id).await? + 1),
+            None => Ok(0),
+        }
+    }
+
+    async fn pause(&self, guild_id: GuildId) -> Result<(), BackendError> {
+        let client = self.client_or_reject(guild_id, "Not playing so can't pause")?;
+        let nodes = client.nodes().await;
+        let playing = match nodes.get(&guild_id.0) {
+            Some(node) => node.now_playing.is_some() && !node.is_paused,
+            None => false,
+        };
+        if !playing {
+            return Err(BackendError::Rejected(
+                "Not playing so can't pause".to_string(),
+            ));
+        }
+
+        client.set_pause(guild_id, true).await?;
+        // Lavalink doesn't report pauses back, keep track here.
+        if let Some(mut node) = nodes.get_mut(&guild_id.0) {
+            node.is_paused = true;
+        }
+        Ok(())
+    }
+
+    async fn resume(&self, guild_id: GuildId) -> Result<(), BackendError> {
+        let client = self.client_or_reject(guild_id, "Not paused so can't unpause")?;
+        let nodes = client.nodes().await;
+        let paused = match nodes.get(&guild_id.0) {
+            Some(node) => node.now_playing.is_some() && node.is_paused,
+            None => false,
+        };
+        if !paused {
+            return Err(BackendError::Rejected(
+                "Not paused so can't unpause".to_string(),
+            ));
+        }
+
+        client.set_pause(guild_id, false).await?;
+        if let Some(mut node) = nodes.get_mut(&guild_id.0) {
+            node.is_paused = false;
+        }
+        Ok(())
+    }
+
+    async fn seek(
+        &self,
+        guild_id: GuildId,
+        position: Duration,
+    ) -> Result<Option<Duration>, BackendError> {
+        let client = self.client_or_reject(guild_id, "Nothing is playing")?;
+        let info = match client.nodes().await.get(&guild_id.0) {
+            Some(node) => node
+                .now_playing
+                .as_ref()
+                .and_then(|playing| playing.track.info.clone()),
+            None => None,
+        };
+        let info = match info {
+            Some(info) => info,
+            None => return Err(BackendError::Rejected("Nothing is playing".to_string())),
+        };
+        if !info.is_seekable {
+            return Err(BackendError::Rejected(
+                "The current track can't be seeked".to_string(),
+            ));
+        }
+        let length = Duration::from_millis(info.length);
+        if position >= length {
+            return Err(BackendError::Rejected(format!(
+                "{} is past the end of the track ({})",
+                format_duration(position),
+                format_duration(length)
+            )));
+        }
+
+        client.seek(guild_id, position).await?;
+        Ok(Some(length))
+    }
+
+    async fn set_volume(&self, guild_id: GuildId, volume: u16) -> Result<(), BackendError> {
+        if let Some(client) = self.client(guild_id) {
+            if client.nodes().await.contains_key(&guild_id.0) {
+                client.volume(guild_id, volume).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn now_playing(&self, guild_id: GuildId) -> Result<Option<NowPlaying>, BackendError> {
+        let current = match self.shared.current(guild_id) {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+        let (loop_mode, _) = self.shared.settings(guild_id).await?;
+        let next_up = match loop_mode {
+            LoopMode::Track => Some(current.title().to_string()),
+            _ => self
+                .shared
+                .redis_store()
+                .await?
+                .peek_queue(guild_id)
+                .await?
+                .map(|next| next.name),
+        };
+        let info = match &current.track.info {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let paused = match self.client(guild_id) {
+            Some(client) => match client.nodes().await.get(&guild_id.0) {
+                Some(node) => node.is_paused,
+                None => false,
+            },
+            None => false,
+        };
+
+        let thumbnail = if info.uri.contains("youtube.com") || info.uri.contains("youtu.be") {
+            Some(format!(
+                "https://img.youtube.com/vi/{}/hqdefault.jpg",
+                info.identifier
+            ))
+        } else {
+            None
+        };
+
+        Ok(Some(NowPlaying {
+            title: info.title.clone(),
+            url: Some(info.uri.clone()),
+            thumbnail,
+            requester: Some(current.requester),
+            elapsed: self.position(guild_id).await?.unwrap_or_default(),
+            duration: current.length(),
+            live: info.is_stream,
+            paused,
+            next_up,
+            loop_mode: Some(loop_mode),
+        }))
+    }
+
+    /// Only as fresh as the last player update from Lavalink.
+    async fn position(&self, guild_id: GuildId) -> Result<Option<Duration>, BackendError> {
+        let client = match self.client(guild_id) {
+            Some(client) => client,
+            None => return Ok(None),
+        };
+        Ok(match client.nodes().await.get(&guild_id.0) {
+            Some(node) => node
+                .now_playing
+                .as_ref()
+                .and_then(|playing| playing.track.info.as_ref())
+                .map(|info| Duration::from_millis(info.position)),
+            None => None,
+        })
+    }
+
+    /// Rejoins the voice channels the bot was playing in before it restarted
+    /// and resumes the tracks about where they stopped.
+    async fn restore_sessions(&self, guild_ids: &[GuildId]) {
+        let mut redis_store = match self.shared.redis_store().await {
+            Ok(redis_store) => redis_store,
+            Err(why) => {
+                error!("Failed to get Redis connection: {:?}", why);
+                return;
+            }
+        };
+
+        for &guild_id in guild_ids {
+            // Ready also fires when a shard reconnects, keep going if we never left.
+            if self.shared.guild_nodes.contains_key(&guild_id) {
+                continue;
+            }
+            let session = match redis_store.get_session(guild_id).await {
+                Ok(Some(session)) => session,
+                Ok(None) => continue,
+                Err(why) => {
+                    error!("Failed to get the session for {}: {:?}", guild_id, why);
+                    continue;
+                }
+            };
+            // A session is only picked up once, whether that works or not.
+            if let Err(why) = redis_store.remove_session(guild_id).await {
+                error!("Failed to remove the session: {:?}", why);
+            }
+            if let Err(why) = self.restore_session(guild_id, &session).await {
+                error!("Failed to restore the session for {}: {}", guild_id, why);
+                self.shared
+                    .announce(
+                        session.text_channel_id,
+                        "I was restarted and couldn't pick up where I left off".to_string(),
+                    )
+                    .await;
+            }
+        }
+    }
+
+    /// Saves the session of every guild for the next start, lets their text
+    /// channels know and leaves voice.
+    async fn suspend(&self) {
+        let guild_ids: Vec<GuildId> = self
+            .shared
+            .guild_nodes
+            .iter()
+            .map(|entry| *entry.key())
+            .collect();
+
+        for guild_id in guild_ids {
+            if let Err(why) = self.suspend_guild(guild_id).await {
+                error!("Failed to suspend the player of {}: {}", guild_id, why);
+            }
+        }
+    }
+
+    /// The queue is kept in Redis, so it can be looked at and changed even
+    /// while nothing plays.
+    async fn queue(&self, guild_id: GuildId) -> Result<QueueSnapshot, BackendError> {
+        let current = self.shared.current(guild_id);
+        let paused = match self.client(guild_id) {
+            Some(client) => match client.nodes().await.get(&guild_id.0) {
+                Some(node) => node.is_paused,
+                None => false,
+            },
+            None => false,
+        };
+        let (loop_mode, fair_scheduling) = self.shared.settings(guild_id).await?;
+        let songs = self
+            .shared
+            .redis_store()
+            .await?
+            .get_queue(guild_id)
+            .await?
+            .unwrap_or_default();
+        Ok(QueueSnapshot {
+            current: current.map(|current| {
+                let playback = if paused {
+                    Playback::Paused
+                } else {
+                    Playback::Playing
+                };
+                (current.song(), playback)
+            }),
+            elapsed: self.position(guild_id).await?.unwrap_or_default(),
+            songs,
+            loop_mode,
+            fair_scheduling,
+        })
+    }
+
+    async fn edit_queue(
+        &self,
+        guild_id: GuildId,
+        edit: QueueEdit,
+    ) -> Result<QueueEdited, BackendError> {
+        let (_, fair_scheduling) = self.shared.settings(guild_id).await?;
+        let _lock = self.shared.lock_queue(guild_id).await;
+        let mut queue = self.shared.load_queue(guild_id).await?;
+        let songs = edit.apply(
+            &mut queue,
+            fair_scheduling,
+            self.shared.current_requester(guild_id),
+        )?;
+        self.shared.save_queue(guild_id, &queue).await?;
+        Ok(QueueEdited {
+            songs,
+            queue_len: queue.len(),
+        })
+    }
+
+    async fn set_loop_mode(
+        &self,
+        guild_id: GuildId,
+        mode: Option<LoopMode>,
+    ) -> Result<LoopMode, BackendError> {
+        self.client_or_reject(guild_id, "Nothing is playing, start something first")?;
+        let (loop_mode, _) = self.shared.settings(guild_id).await?;
+        let loop_mode = mode.unwrap_or_else(|| loop_mode.next());
+        self.shared
+            .redis_store()
+            .await?
+            .set_loop_mode(guild_id, &loop_mode.to_string())
+            .await?;
+        Ok(loop_mode)
+    }
+
+    async fn set_fair_scheduling(
+        &self,
+        guild_id: GuildId,
+        enabled: Option<bool>,
+    ) -> Result<bool, BackendError> {
+        self.client_or_reject(guild_id, "Nothing is playing, start something first")?;
+        let (_, fair_scheduling) = self.shared.settings(guild_id).await?;
+        let fair_scheduling = enabled.unwrap_or(!fair_scheduling);
+        self.shared
+            .redis_store()
+            .await?
+            .set_fair_scheduling(guild_id, fair_scheduling)
+            .await?;
+        if fair_scheduling {
+            let _lock = self.shared.lock_queue(guild_id).await;
+            let mut queue = self.shared.load_queue(guild_id).await?;
+            queue.interleave_by_requester(self.shared.current_requester(guild_id));
+            self.shared.save_queue(guild_id, &queue).await?;
+        }
+        Ok(fair_scheduling)
+    }
+}
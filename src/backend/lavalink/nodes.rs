@@ -0,0 +1,179 @@
+use lavalink_rs::model::Stats;
+use lavalink_rs::LavalinkClient;
+use songbird::ConnectionInfo;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use super::socket::NodeConfig;
+
+/// How busy a node is and when it was last heard from, kept up to date from
+/// its events.
+#[derive(Debug)]
+pub struct NodeHealth {
+    inner: Mutex<Health>,
+}
+
+#[derive(Debug)]
+struct Health {
+    penalty: f64,
+    last_seen: Instant,
+    /// Counts the connections made to the node, so the end of an old one
+    /// isn't taken for the end of the current one.
+    connection: u64,
+    connected: bool,
+    down: bool,
+}
+
+impl Default for NodeHealth {
+    fn default() -> Self {
+        NodeHealth {
+            inner: Mutex::new(Health {
+                penalty: 0.0,
+                last_seen: Instant::now(),
+                connection: 0,
+                connected: false,
+                down: false,
+            }),
+        }
+    }
+}
+
+impl NodeHealth {
+    /// Notes that the node sent something.
+    pub fn seen(&self) {
+        self.inner.lock().unwrap().last_seen = Instant::now();
+    }
+
+    pub fn update(&self, stats: &Stats) {
+        let mut health = self.inner.lock().unwrap();
+        health.penalty = penalty(stats);
+        health.last_seen = Instant::now();
+    }
+
+    pub fn penalty(&self) -> f64 {
+        self.inner.lock().unwrap().penalty
+    }
+
+    pub fn silent_for(&self) -> Duration {
+        self.inner.lock().unwrap().last_seen.elapsed()
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.inner.lock().unwrap().down
+    }
+
+    pub fn mark_down(&self) {
+        self.inner.lock().unwrap().down = true;
+    }
+
+    /// Notes a new connection to the node, which is up again from here.
+    /// Returns the number to report its end with.
+    pub fn connected(&self) -> u64 {
+        let mut health = self.inner.lock().unwrap();
+        health.connection += 1;
+        health.connected = true;
+        health.down = false;
+        health.last_seen = Instant::now();
+        health.connection
+    }
+
+    pub fn disconnected(&self, connection: u64) {
+        let mut health = self.inner.lock().unwrap();
+        if health.connection == connection {
+            health.connected = false;
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.inner.lock().unwrap().connected
+    }
+}
+
+/// Scores how loaded a node is, lower is better. These are the penalties
+/// Lavalink's own clients balance on: players, CPU load and, when the node
+/// reports them, frames it failed to send.
+fn penalty(stats: &Stats) -> f64 {
+    let players = stats.playing_players as f64;
+    let cpu = 1.05f64.powf(100.0 * stats.cpu.system_load) * 10.0 - 10.0;
+    let frames = match &stats.frame_stats {
+        Some(frames) => {
+            let deficit = 1.03f64.powf(500.0 * (frames.deficit as f64 / 3000.0)) * 600.0 - 600.0;
+            let nulled =
+                (1.03f64.powf(500.0 * (frames.nulled as f64 / 3000.0)) * 300.0 - 300.0) * 2.0;
+            deficit + nulled
+        }
+        None => 0.0,
+    };
+    players + cpu + frames
+}
+
+/// A Lavalink server the bot can play on.
+pub struct LavalinkNode {
+    pub config: NodeConfig,
+    /// Replaced on every reconnect.
+    client: RwLock<LavalinkClient>,
+    pub health: Arc<NodeHealth>,
+}
+
+impl LavalinkNode {
+    pub fn new(config: NodeConfig, client: LavalinkClient, health: Arc<NodeHealth>) -> Self {
+        LavalinkNode {
+            config,
+            client: RwLock::new(client),
+            health,
+        }
+    }
+
+    pub fn client(&self) -> LavalinkClient {
+        self.client.read().unwrap().clone()
+    }
+
+    pub fn set_client(&self, client: LavalinkClient) {
+        *self.client.write().unwrap() = client;
+    }
+
+    /// `host:port`, to name the node in logs.
+    pub fn address(&self) -> String {
+        self.config.address()
+    }
+}
+
+/// Which node a guild plays on, and the voice connection it was handed so
+/// another node can take over.
+#[derive(Clone)]
+pub struct GuildNode {
+    pub node: usize,
+    pub connection: ConnectionInfo,
+}
+
+/// The least loaded node that is up, if any.
+pub fn least_loaded(nodes: &[LavalinkNode]) -> Option<usize> {
+    nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| !node.health.is_down())
+        .min_by(|(_, a), (_, b)| {
+            a.health
+                .penalty()
+                .partial_cmp(&b.health.penalty())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .map(|(index, _)| index)
+}
+
+/// Reads a comma separated list of `host:port` addresses.
+pub fn parse_addresses(addresses: &str) -> Vec<(String, u16)> {
+    addresses
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| match address.rsplit_once(':') {
+            Some((host, port)) => (
+                host.to_string(),
+                port.parse()
+                    .unwrap_or_else(|_| panic!("Invalid port in Lavalink node {}", address)),
+            ),
+            None => panic!("Expected host:port for Lavalink node {}", address),
+        })
+        .collect()
+}
@@ -0,0 +1,67 @@
+use lavalink_rs::model::Track;
+use serenity::model::id::{ChannelId, UserId};
+use std::time::Duration;
+
+use crate::redis_store::{PlayArgs, QueuedSong};
+
+/// A track someone asked for, and where to announce it.
+#[derive(Debug, Clone)]
+pub struct QueuedTrack {
+    pub track: Track,
+    pub requester: UserId,
+    pub text_channel_id: ChannelId,
+    /// What to search for to find another take on the track should it fail.
+    pub search: String,
+    /// Whether this is already the replacement of a track that failed.
+    pub retried: bool,
+}
+
+impl QueuedTrack {
+    /// The queued song once Lavalink loaded it. Alternatives to it are
+    /// searched for by its name.
+    pub fn from_song(track: Track, song: &QueuedSong) -> Self {
+        QueuedTrack {
+            track,
+            requester: song.requester,
+            text_channel_id: song.channel_id,
+            search: song.name.clone(),
+            retried: false,
+        }
+    }
+
+    /// How the track is kept in the queue in Redis, by its link so it loads
+    /// again as the same track.
+    pub fn song(&self) -> QueuedSong {
+        QueuedSong {
+            channel_id: self.text_channel_id,
+            name: self.title().to_string(),
+            play: PlayArgs::YoutubeLink(self.uri().to_string()),
+            requester: self.requester,
+            duration: self.length(),
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        self.track
+            .info
+            .as_ref()
+            .map(|info| info.title.as_str())
+            .unwrap_or("Unknown track")
+    }
+
+    pub fn uri(&self) -> &str {
+        self.track
+            .info
+            .as_ref()
+            .map(|info| info.uri.as_str())
+            .unwrap_or("-")
+    }
+
+    /// Unknown for live streams.
+    pub fn length(&self) -> Option<Duration> {
+        match &self.track.info {
+            Some(info) if !info.is_stream => Some(Duration::from_millis(info.length)),
+            _ => None,
+        }
+    }
+}
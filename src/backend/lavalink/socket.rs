@@ -0,0 +1,274 @@
+//! The websocket to a node, opened here rather than by
+//! `LavalinkClient::builder`. lavalink-rs fixes the headers it connects with,
+//! which leaves no way to hand Lavalink a resume key.
+
+use async_tungstenite::tokio::connect_async;
+use async_tungstenite::tungstenite::http::Request;
+use async_tungstenite::tungstenite::Message;
+use futures::stream::{SplitStream, StreamExt};
+use futures::SinkExt;
+use lavalink_rs::error::LavalinkError;
+use lavalink_rs::gateway::LavalinkEventHandler;
+use lavalink_rs::model::{GatewayEvent, PlayerUpdate, Stats, TrackFinish, TrackStart};
+use lavalink_rs::{LavalinkClient, LavalinkClientInner, WsStream};
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+use serenity::async_trait;
+use serenity::model::id::UserId;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use super::nodes::NodeHealth;
+
+/// How long Lavalink keeps the players of a dropped connection around for
+/// it to be resumed.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where a node is and how to log in to it.
+#[derive(Clone)]
+pub struct NodeConfig {
+    pub host: String,
+    pub port: u16,
+    pub password: String,
+    pub bot_id: UserId,
+}
+
+impl NodeConfig {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    fn resume_key(&self) -> String {
+        format!("disco-music-bot-{}-{}", self.bot_id, self.address())
+    }
+}
+
+/// A track threw while playing or couldn't be loaded.
+#[derive(Debug, Clone)]
+pub struct TrackException {
+    pub guild_id: u64,
+    pub track: String,
+    pub message: String,
+}
+
+/// A track stopped sending audio for longer than Lavalink's threshold.
+#[derive(Debug, Clone)]
+pub struct TrackStuck {
+    pub guild_id: u64,
+    pub track: String,
+    pub threshold: Duration,
+}
+
+/// Discord closed the node's voice connection for a guild.
+#[derive(Debug, Clone)]
+pub struct VoiceClosed {
+    pub guild_id: u64,
+    pub code: u64,
+    pub reason: String,
+    pub by_remote: bool,
+}
+
+/// Lavalink sends guild ids as strings.
+fn guild_id(event: &Value) -> Option<u64> {
+    event["guildId"].as_str()?.parse().ok()
+}
+
+impl TrackException {
+    fn from_event(event: &Value) -> Option<Self> {
+        // Lavalink moved the message into `exception` at some point.
+        let message = event["exception"]["message"]
+            .as_str()
+            .or_else(|| event["error"].as_str())
+            .unwrap_or("Unknown error");
+        Some(TrackException {
+            guild_id: guild_id(event)?,
+            track: event["track"].as_str()?.to_string(),
+            message: message.to_string(),
+        })
+    }
+}
+
+impl TrackStuck {
+    fn from_event(event: &Value) -> Option<Self> {
+        Some(TrackStuck {
+            guild_id: guild_id(event)?,
+            track: event["track"].as_str()?.to_string(),
+            threshold: Duration::from_millis(event["thresholdMs"].as_u64().unwrap_or_default()),
+        })
+    }
+}
+
+impl VoiceClosed {
+    fn from_event(event: &Value) -> Option<Self> {
+        Some(VoiceClosed {
+            guild_id: guild_id(event)?,
+            code: event["code"].as_u64()?,
+            reason: event["reason"].as_str().unwrap_or_default().to_string(),
+            by_remote: event["byRemote"].as_bool().unwrap_or_default(),
+        })
+    }
+}
+
+/// The events `LavalinkEventHandler` has no place for.
+#[async_trait]
+pub trait NodeEventHandler: LavalinkEventHandler {
+    async fn track_exception(&self, client: LavalinkClient, event: TrackException);
+
+    async fn track_stuck(&self, client: LavalinkClient, event: TrackStuck);
+
+    async fn voice_closed(&self, client: LavalinkClient, event: VoiceClosed);
+}
+
+/// Connects to the node and has Lavalink hold on to the players for
+/// `RESUME_TIMEOUT` should the connection drop. When reconnecting, pass the
+/// client of the dropped connection; its players carry over, and the returned
+/// flag tells whether Lavalink still had them.
+///
+/// Events go to `handler` until the connection closes, then `health` hears
+/// about it.
+pub async fn connect<H>(
+    config: &NodeConfig,
+    previous: Option<&LavalinkClient>,
+    handler: Arc<H>,
+    health: Arc<NodeHealth>,
+) -> Result<(LavalinkClient, bool), LavalinkError>
+where
+    H: NodeEventHandler + Send + Sync + 'static,
+{
+    let mut headers = HeaderMap::new();
+    headers.insert("Authorization", config.password.parse()?);
+    headers.insert("Num-Shards", "1".parse()?);
+    headers.insert("User-Id", config.bot_id.to_string().parse()?);
+    headers.insert("Resume-Key", config.resume_key().parse()?);
+
+    let mut request = Request::builder()
+        .uri(format!("ws://{}", config.address()))
+        .body(())
+        .expect("The node address makes a valid URI");
+    *request.headers_mut() = headers.clone();
+    let (stream, response) = connect_async(request).await?;
+    let resumed = response
+        .headers()
+        .get("Session-Resumed")
+        .map_or(false, |resumed| resumed == "true");
+    let (socket_write, socket_read) = stream.split();
+
+    let client = LavalinkClient {
+        inner: Arc::new(Mutex::new(LavalinkClientInner {
+            rest_uri: format!("http://{}", config.address()),
+            headers,
+            socket_write,
+            nodes: match previous {
+                Some(previous) => previous.nodes().await,
+                None => Arc::default(),
+            },
+            loops: Arc::default(),
+        })),
+    };
+
+    let configure = serde_json::json!({
+        "op": "configureResuming",
+        "key": config.resume_key(),
+        "timeout": RESUME_TIMEOUT.as_secs(),
+    });
+    client
+        .inner
+        .lock()
+        .await
+        .socket_write
+        .send(Message::Text(configure.to_string()))
+        .await?;
+
+    let connection = health.connected();
+    tokio::spawn(event_loop(
+        socket_read,
+        handler,
+        client.clone(),
+        health,
+        connection,
+    ));
+    Ok((client, resumed))
+}
+
+/// Hands the node's events to the handler, the way lavalink-rs' own loop
+/// does, keeping the position of the playing track up to date on the way.
+/// Also passes on the events lavalink-rs drops.
+async fn event_loop<H>(
+    mut read: SplitStream<WsStream>,
+    handler: Arc<H>,
+    client: LavalinkClient,
+    health: Arc<NodeHealth>,
+    connection: u64,
+) where
+    H: NodeEventHandler + Send + Sync + 'static,
+{
+    while let Some(Ok(message)) = read.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            _ => continue,
+        };
+        let event = match serde_json::from_str::<GatewayEvent>(&text) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        match (event.op.as_str(), event.event_type.as_deref()) {
+            ("stats", _) => {
+                if let Ok(stats) = serde_json::from_str::<Stats>(&text) {
+                    handler.stats(client.clone(), stats).await;
+                }
+            }
+            ("playerUpdate", _) => {
+                if let Ok(update) = serde_json::from_str::<PlayerUpdate>(&text) {
+                    if let Some(mut node) = client.nodes().await.get_mut(&update.guild_id) {
+                        let info = node
+                            .now_playing
+                            .as_mut()
+                            .and_then(|playing| playing.track.info.as_mut());
+                        if let Some(info) = info {
+                            info.position = update.state.position.max(0) as u64;
+                        }
+                    }
+                    handler.player_update(client.clone(), update).await;
+                }
+            }
+            ("event", Some("TrackStartEvent")) => {
+                if let Ok(start) = serde_json::from_str::<TrackStart>(&text) {
+                    handler.track_start(client.clone(), start).await;
+                }
+            }
+            ("event", Some("TrackEndEvent")) => {
+                if let Ok(finish) = serde_json::from_str::<TrackFinish>(&text) {
+                    handler.track_finish(client.clone(), finish).await;
+                }
+            }
+            ("event", Some(event_type)) => {
+                let event: Value = match serde_json::from_str(&text) {
+                    Ok(event) => event,
+                    Err(_) => continue,
+                };
+                match event_type {
+                    "TrackExceptionEvent" => {
+                        if let Some(exception) = TrackException::from_event(&event) {
+                            handler.track_exception(client.clone(), exception).await;
+                        }
+                    }
+                    "TrackStuckEvent" => {
+                        if let Some(stuck) = TrackStuck::from_event(&event) {
+                            handler.track_stuck(client.clone(), stuck).await;
+                        }
+                    }
+                    "WebSocketClosedEvent" => {
+                        if let Some(closed) = VoiceClosed::from_event(&event) {
+                            handler.voice_closed(client.clone(), closed).await;
+                        }
+                    }
+                    _ => warn!("Unknown Lavalink event: {}", text),
+                }
+            }
+            _ => warn!("Unknown Lavalink message: {}", text),
+        }
+    }
+    health.disconnected(connection);
+}
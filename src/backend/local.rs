@@ -0,0 +1,487 @@
+use serenity::async_trait;
+use serenity::model::prelude::*;
+use songbird::Songbird;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{
+    BackendError, EnqueueOutcome, MusicBackend, NowPlaying, Playback, QueueSnapshot, TrackRequest,
+};
+use crate::commands::music::{cache_resolved_track, send_msg, stored_volume, LoopMode, SongQueue};
+use crate::commands::player::{
+    GuildPlayer, MusicState, PlayOutcome, PlayerError, PlayerState, QueueEdit, QueueEdited,
+};
+use crate::redis_store::{PlayArgs, QueuedSong, RedisStore, RedisStoreError, TrackMetadata};
+use crate::search::{ytdl_metadata, SearchError, SearchProvider};
+
+/// Plays through songbird in this process, with a `GuildPlayer` per guild.
+pub struct LocalBackend {
+    manager: Arc<Songbird>,
+    redis_client: redis::Client,
+    search_provider: Arc<dyn SearchProvider>,
+    music_state: Arc<MusicState>,
+}
+
+impl LocalBackend {
+    pub fn new(
+        manager: Arc<Songbird>,
+        redis_client: redis::Client,
+        search_provider: Arc<dyn SearchProvider>,
+        music_state: Arc<MusicState>,
+    ) -> Self {
+        LocalBackend {
+            manager,
+            redis_client,
+            search_provider,
+            music_state,
+        }
+    }
+
+    fn player(&self, guild_id: GuildId) -> Option<GuildPlayer> {
+        self.music_state
+            .players
+            .get(&guild_id)
+            .map(|player| player.clone())
+    }
+
+    async fn redis_store(&self) -> Result<RedisStore, RedisStoreError> {
+        Ok(RedisStore::new(
+            self.redis_client.get_async_connection().await?,
+        ))
+    }
+
+    /// Loads the queue and settings the last player left in Redis, for when
+    /// no player is running.
+    async fn stored_queue(
+        &self,
+        guild_id: GuildId,
+    ) -> Result<(SongQueue, LoopMode, bool), RedisStoreError> {
+        let mut redis_store = self.redis_store().await?;
+        let songs = redis_store.get_queue(guild_id).await?.unwrap_or_default();
+        let loop_mode = redis_store
+            .get_loop_mode(guild_id)
+            .await?
+            .and_then(|mode| mode.parse().ok())
+            .unwrap_or_default();
+        let fair_scheduling = redis_store
+            .get_fair_scheduling(guild_id)
+            .await?
+            .unwrap_or_default();
+        Ok((SongQueue::from(songs), loop_mode, fair_scheduling))
+    }
+
+    /// Edits the queue in Redis directly while no player is running.
+    async fn edit_stored_queue(
+        &self,
+        guild_id: GuildId,
+        edit: QueueEdit,
+    ) -> Result<QueueEdited, BackendError> {
+        let (mut queue, _, fair_scheduling) = self.stored_queue(guild_id).await?;
+        let songs = edit.apply(&mut queue, fair_scheduling, None)?;
+        self.redis_store()
+            .await?
+            .set_queue(guild_id, queue.iter())
+            .await?;
+        Ok(QueueEdited {
+            songs,
+            queue_len: queue.len(),
+        })
+    }
+
+    async fn player_or_spawn(&self, guild_id: GuildId, text_channel_id: ChannelId) -> GuildPlayer {
+        if let Some(player) = self.player(guild_id) {
+            return player;
+        }
+        let volume = stored_volume(&self.redis_client, guild_id).await;
+        // Another command may have set the guild up in the meantime.
+        let player = self
+            .music_state
+            .players
+            .entry(guild_id)
+            .or_insert_with(|| {
+                GuildPlayer::spawn(
+                    guild_id,
+                    text_channel_id,
+                    volume,
+                    self.manager.clone(),
+                    self.redis_client.clone(),
+                    &self.music_state,
+                )
+            })
+            .clone();
+        player
+    }
+
+    /// Looks the song up in the track cache, so queueing it doesn't need
+    /// youtube-dl.
+    async fn cached_track(&self, play_arg: &PlayArgs) -> Option<TrackMetadata> {
+        let mut redis_store = match self.redis_client.get_async_connection().await {
+            Ok(conn) => RedisStore::new(conn),
+            Err(e) => {
+                eprintln!("Failed to get Redis connection : {}", e);
+                return None;
+            }
+        };
+        let cached = match play_arg {
+            PlayArgs::SearchQuery(query) => redis_store.get_cached_query(query).await,
+            PlayArgs::YoutubeLink(url) => redis_store.get_cached_track(url).await,
+        };
+        cached
+            .map_err(|why| eprintln!("Failed to read the track cache : {:?}", why))
+            .ok()
+            .flatten()
+    }
+
+    /// Finds out what a song is without fetching any audio, so it can be
+    /// queued. Tries the track cache first, then the search provider for
+    /// search queries or youtube-dl's metadata for links. The audio itself is
+    /// only resolved once the song is about to play.
+    async fn resolve_track(&self, play_arg: &PlayArgs) -> Result<TrackMetadata, SearchError> {
+        if let Some(track) = self.cached_track(play_arg).await {
+            return Ok(track);
+        }
+        let track = match play_arg {
+            PlayArgs::SearchQuery(query) => self
+                .search_provider
+                .search(query, 1)
+                .await?
+                .into_iter()
+                .next()
+                .ok_or(SearchError::NotFound)?,
+            PlayArgs::YoutubeLink(url) => ytdl_metadata(url).await?,
+        };
+        cache_resolved_track(&self.redis_client, play_arg, Some(track.clone())).await;
+        Ok(track)
+    }
+}
+
+/// Players shut down once idle, so one that was looked up just before may be
+/// gone by the time it gets a command. That counts as no player.
+fn live<T>(result: Result<T, PlayerError>) -> Result<Option<T>, PlayerError> {
+    match result {
+        Err(PlayerError::Closed) => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+#[async_trait]
+impl MusicBackend for LocalBackend {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn player_count(&self) -> usize {
+        self.music_state.players.len()
+    }
+
+    async fn join(&self, guild_id: GuildId, channel_id: ChannelId) -> Result<(), BackendError> {
+        let (_, joined) = self.manager.join(guild_id, channel_id).await;
+        joined?;
+        Ok(())
+    }
+
+    async fn leave(&self, guild_id: GuildId) -> Result<(), BackendError> {
+        let left = match self.player(guild_id) {
+            Some(player) => live(player.leave().await)?,
+            None => None,
+        };
+        match left {
+            Some(()) => {}
+            None if self.manager.get(guild_id).is_some() => self.manager.leave(guild_id).await?,
+            None => return Err(BackendError::Rejected("Not in a voice channel".to_string())),
+        }
+        Ok(())
+    }
+
+    async fn enqueue(
+        &self,
+        guild_id: GuildId,
+        request: TrackRequest,
+    ) -> Result<EnqueueOutcome, BackendError> {
+        let player = self
+            .player_or_spawn(guild_id, request.text_channel_id)
+            .await;
+
+        // Search queries always go through the search provider, so the
+        // player only ever gets links to play. A link that starts right away
+        // needs no details up front, the player fills them in.
+        let busy = match live(player.status().await)? {
+            Some(status) => status.state.is_busy(),
+            None => false,
+        };
+        let resolve = busy || matches!(request.play, PlayArgs::SearchQuery(_));
+        let song = if resolve {
+            match self.resolve_track(&request.play).await {
+                Ok(track) => {
+                    QueuedSong::from_track(&track, request.text_channel_id, request.requester)
+                }
+                Err(why) => {
+                    eprintln!("Error: {:?}", why);
+                    return Err(BackendError::Rejected(format!("Error: {}", why)));
+                }
+            }
+        } else {
+            QueuedSong::from_play_args(request.play, request.text_channel_id, request.requester)
+        };
+        let name = song.name.clone();
+
+        let played = player
+            .play(
+                song.clone(),
+                request.voice_channel_id,
+                request.text_channel_id,
+                request.next,
+            )
+            .await;
+        let outcome = match live(played)? {
+            Some(outcome) => outcome,
+            // The player shut down for being idle while the song was looked
+            // up. A new one picks the queue up from Redis.
+            None => {
+                self.player_or_spawn(guild_id, request.text_channel_id)
+                    .await
+                    .play(
+                        song,
+                        request.voice_channel_id,
+                        request.text_channel_id,
+                        request.next,
+                    )
+                    .await?
+            }
+        };
+        Ok(match outcome {
+            PlayOutcome::Started => EnqueueOutcome::Started,
+            PlayOutcome::Queued(queued) => EnqueueOutcome::Queued { name, queued },
+        })
+    }
+
+    async fn skip(&self, guild_id: GuildId, count: usize) -> Result<usize, BackendError> {
+        let skipped = match self.player(guild_id) {
+            Some(player) => live(player.skip(count).await)?,
+            None => None,
+        };
+        skipped.ok_or_else(|| BackendError::Rejected("Nothing to skip".to_string()))
+    }
+
+    async fn pause(&self, guild_id: GuildId) -> Result<(), BackendError> {
+        let paused = match self.player(guild_id) {
+            Some(player) => live(player.pause().await)?,
+            None => None,
+        };
+        paused.ok_or_else(|| BackendError::Rejected("Not playing so can't pause".to_string()))
+    }
+
+    async fn resume(&self, guild_id: GuildId) -> Result<(), BackendError> {
+        let resumed = match self.player(guild_id) {
+            Some(player) => live(player.unpause().await)?,
+            None => None,
+        };
+        resumed.ok_or_else(|| BackendError::Rejected("Not paused so can't unpause".to_string()))
+    }
+
+    async fn seek(
+        &self,
+        guild_id: GuildId,
+        position: Duration,
+    ) -> Result<Option<Duration>, BackendError> {
+        let seeked = match self.player(guild_id) {
+            Some(player) => live(player.seek(position).await)?,
+            None => None,
+        };
+        seeked.ok_or_else(|| BackendError::Rejected("Nothing is playing".to_string()))
+    }
+
+    async fn set_volume(&self, guild_id: GuildId, volume: u16) -> Result<(), BackendError> {
+        if let Some(player) = self.player(guild_id) {
+            live(player.set_volume(volume).await)?;
+        }
+        Ok(())
+    }
+
+    async fn now_playing(&self, guild_id: GuildId) -> Result<Option<NowPlaying>, BackendError> {
+        let snapshot = match self.player(guild_id) {
+            Some(player) => live(player.snapshot().await)?,
+            None => None,
+        };
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => return Ok(None),
+        };
+        let (song, paused) = match &snapshot.state {
+            PlayerState::Playing { song, .. } => (song, false),
+            PlayerState::Paused { song, .. } => (song, true),
+            _ => return Ok(None),
+        };
+        let next_up = match snapshot.loop_mode {
+            LoopMode::Track => Some(song),
+            _ => snapshot.queue.get(0),
+        };
+        let metadata = snapshot.metadata.as_ref();
+
+        Ok(Some(NowPlaying {
+            title: song.name.clone(),
+            url: metadata.and_then(|m| m.source_url.clone()),
+            thumbnail: metadata.and_then(|m| m.thumbnail.clone()),
+            requester: Some(song.requester),
+            elapsed: snapshot.elapsed,
+            duration: song.duration,
+            live: false,
+            paused,
+            next_up: next_up.map(|song| song.name.clone()),
+            loop_mode: Some(snapshot.loop_mode),
+        }))
+    }
+
+    async fn position(&self, guild_id: GuildId) -> Result<Option<Duration>, BackendError> {
+        let status = match self.player(guild_id) {
+            Some(player) => live(player.status().await)?,
+            None => None,
+        };
+        let status = match status {
+            Some(status) => status,
+            None => return Ok(None),
+        };
+        Ok(match status.state {
+            PlayerState::Playing { .. } | PlayerState::Paused { .. } => Some(status.elapsed),
+            _ => None,
+        })
+    }
+
+    async fn queue(&self, guild_id: GuildId) -> Result<QueueSnapshot, BackendError> {
+        let snapshot = match self.player(guild_id) {
+            Some(player) => live(player.snapshot().await)?,
+            None => None,
+        };
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            // The queue outlives the player, nothing plays until the next one
+            // picks it up.
+            None => {
+                let (queue, loop_mode, fair_scheduling) = self.stored_queue(guild_id).await?;
+                return Ok(QueueSnapshot {
+                    current: None,
+                    elapsed: Duration::ZERO,
+                    songs: queue.iter().cloned().collect(),
+                    loop_mode,
+                    fair_scheduling,
+                });
+            }
+        };
+        let current = match snapshot.state {
+            PlayerState::Playing { song, .. } => Some((song, Playback::Playing)),
+            PlayerState::Paused { song, .. } => Some((song, Playback::Paused)),
+            PlayerState::Connecting { song, .. } | PlayerState::Buffering { song } => {
+                Some((song, Playback::Starting))
+            }
+            PlayerState::Idle | PlayerState::Disconnected => None,
+        };
+        Ok(QueueSnapshot {
+            current,
+            elapsed: snapshot.elapsed,
+            songs: snapshot.queue.iter().cloned().collect(),
+            loop_mode: snapshot.loop_mode,
+            fair_scheduling: snapshot.fair_scheduling,
+        })
+    }
+
+    async fn edit_queue(
+        &self,
+        guild_id: GuildId,
+        edit: QueueEdit,
+    ) -> Result<QueueEdited, BackendError> {
+        let player = match self.player(guild_id) {
+            Some(player) => player,
+            None => return self.edit_stored_queue(guild_id, edit).await,
+        };
+        match live(player.edit_queue(edit).await)? {
+            Some(edited) => Ok(edited),
+            None => self.edit_stored_queue(guild_id, edit).await,
+        }
+    }
+
+    async fn set_loop_mode(
+        &self,
+        guild_id: GuildId,
+        mode: Option<LoopMode>,
+    ) -> Result<LoopMode, BackendError> {
+        let loop_mode = match self.player(guild_id) {
+            Some(player) => live(player.set_loop_mode(mode).await)?,
+            None => None,
+        };
+        loop_mode.ok_or_else(|| {
+            BackendError::Rejected("Nothing is playing, start something first".to_string())
+        })
+    }
+
+    async fn set_fair_scheduling(
+        &self,
+        guild_id: GuildId,
+        enabled: Option<bool>,
+    ) -> Result<bool, BackendError> {
+        let fair_scheduling = match self.player(guild_id) {
+            Some(player) => live(player.set_fair_scheduling(enabled).await)?,
+            None => None,
+        };
+        fair_scheduling.ok_or_else(|| {
+            BackendError::Rejected("Nothing is playing, start something first".to_string())
+        })
+    }
+
+    /// Rejoins the voice channels the bot was playing in before it restarted
+    /// and resumes the songs about where they stopped.
+    async fn restore_sessions(&self, guild_ids: &[GuildId]) {
+        let mut redis_store = match self.redis_client.get_async_connection().await {
+            Ok(conn) => RedisStore::new(conn),
+            Err(e) => {
+                eprintln!("Failed to get Redis connection : {}", e);
+                return;
+            }
+        };
+
+        for &guild_id in guild_ids {
+            // Ready also fires when a shard reconnects, keep going if we never left.
+            if self.player(guild_id).is_some() {
+                continue;
+            }
+            let session = match redis_store.get_session(guild_id).await {
+                Ok(Some(session)) => session,
+                Ok(None) => continue,
+                Err(why) => {
+                    eprintln!("Failed to get the session for {} : {:?}", guild_id, why);
+                    continue;
+                }
+            };
+            let text_channel_id = session.text_channel_id;
+            let player = self.player_or_spawn(guild_id, text_channel_id).await;
+            // The player rejoins and resumes from here, and tells the channel how it went.
+            if let Err(why) = player.restore(session).await {
+                eprintln!("Failed to restore the session for {} : {:?}", guild_id, why);
+                let _ = send_msg(
+                    text_channel_id,
+                    "I was restarted and couldn't pick up where I left off",
+                )
+                .await;
+                if let Err(why) = redis_store.remove_session(guild_id).await {
+                    eprintln!("Failed to remove the session : {:?}", why);
+                }
+            }
+        }
+    }
+
+    /// Saves the session of every guild for the next start, lets the bound
+    /// text channels know and leaves voice.
+    async fn suspend(&self) {
+        let players: Vec<GuildPlayer> = self
+            .music_state
+            .players
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+
+        for player in players {
+            if let Err(why) = live(player.suspend().await) {
+                eprintln!("Failed to suspend the player : {:?}", why);
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Playback backends. The music commands only talk to a `MusicBackend`, so the
+//! same commands work whether the audio is played by songbird in this process
+//! or by a Lavalink node.
+
+pub mod lavalink;
+pub mod local;
+
+use lavalink_rs::error::LavalinkError;
+use serenity::async_trait;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use songbird::error::JoinError;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::commands::music::LoopMode;
+use crate::commands::player::{PlayerError, QueueEdit, QueueEdited};
+use crate::redis_store::{PlayArgs, QueuedSong, RedisStoreError};
+
+pub use self::lavalink::LavalinkBackend;
+pub use self::local::LocalBackend;
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// The request doesn't make sense right now, with a message saying why.
+    Rejected(String),
+    Player(PlayerError),
+    Redis(RedisStoreError),
+    Lavalink(LavalinkError),
+    Join(JoinError),
+}
+
+impl From<PlayerError> for BackendError {
+    fn from(err: PlayerError) -> Self {
+        match err {
+            PlayerError::Rejected(why) => BackendError::Rejected(why),
+            err => BackendError::Player(err),
+        }
+    }
+}
+
+impl From<RedisStoreError> for BackendError {
+    fn from(err: RedisStoreError) -> Self {
+        BackendError::Redis(err)
+    }
+}
+
+impl From<LavalinkError> for BackendError {
+    fn from(err: LavalinkError) -> Self {
+        BackendError::Lavalink(err)
+    }
+}
+
+impl From<JoinError> for BackendError {
+    fn from(err: JoinError) -> Self {
+        BackendError::Join(err)
+    }
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::Rejected(why) => write!(f, "{}", why),
+            BackendError::Player(err) => write!(f, "{}", err),
+            BackendError::Redis(err) => write!(f, "{}", err),
+            BackendError::Lavalink(err) => write!(f, "Lavalink error: {}", err),
+            BackendError::Join(err) => write!(f, "Failed to join: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// A song someone asked for.
+pub struct TrackRequest {
+    pub play: PlayArgs,
+    /// Where to play it, if not already in voice.
+    pub voice_channel_id: ChannelId,
+    /// Where to announce it.
+    pub text_channel_id: ChannelId,
+    pub requester: UserId,
+    /// Queue it in front of everything else.
+    pub next: bool,
+}
+
+/// Where a queued song ended up and about when it starts, unless a single
+/// track is looping.
+pub struct Enqueued {
+    pub position: usize,
+    pub queue_len: usize,
+    pub starts_in: Option<(Duration, bool)>,
+}
+
+pub enum EnqueueOutcome {
+    /// Nothing was playing, the backend lets the channel know once it plays.
+    Started,
+    /// Something else is playing or about to.
+    Queued { name: String, queued: Enqueued },
+}
+
+/// What a guild is listening to right now.
+pub struct NowPlaying {
+    pub title: String,
+    pub url: Option<String>,
+    pub thumbnail: Option<String>,
+    pub requester: Option<UserId>,
+    pub elapsed: Duration,
+    /// Unknown for some tracks, and live streams have none.
+    pub duration: Option<Duration>,
+    pub live: bool,
+    pub paused: bool,
+    pub next_up: Option<String>,
+    /// Only set by backends that can loop.
+    pub loop_mode: Option<LoopMode>,
+}
+
+/// How the song at the front of the queue is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Playback {
+    Playing,
+    Paused,
+    /// Still joining the channel or getting the audio ready.
+    Starting,
+}
+
+/// A guild's queue, with the song that is playing in front of it.
+pub struct QueueSnapshot {
+    pub current: Option<(QueuedSong, Playback)>,
+    /// How far into the current song playback is.
+    pub elapsed: Duration,
+    pub songs: Vec<QueuedSong>,
+    pub loop_mode: LoopMode,
+    pub fair_scheduling: bool,
+}
+
+/// Plays music in guilds. Positions and counts follow the local player: the
+/// queue doesn't count the song that is playing.
+#[async_trait]
+pub trait MusicBackend: Send + Sync {
+    /// A short name for the status command.
+    fn name(&self) -> &'static str;
+
+    /// How many guilds have a player right now.
+    async fn player_count(&self) -> usize;
+
+    async fn join(&self, guild_id: GuildId, channel_id: ChannelId) -> Result<(), BackendError>;
+
+    /// Stops playing and leaves voice. The queue stays for the next time
+    /// something plays.
+    async fn leave(&self, guild_id: GuildId) -> Result<(), BackendError>;
+
+    /// Starts playing the song when idle, joining the requester's channel if
+    /// needed, otherwise queues it.
+    async fn enqueue(
+        &self,
+        guild_id: GuildId,
+        request: TrackRequest,
+    ) -> Result<EnqueueOutcome, BackendError>;
+
+    /// Skips `count` songs, counting the current one. Returns how many songs
+    /// are left, counting the one that plays next.
+    async fn skip(&self, guild_id: GuildId, count: usize) -> Result<usize, BackendError>;
+
+    async fn pause(&self, guild_id: GuildId) -> Result<(), BackendError>;
+
+    async fn resume(&self, guild_id: GuildId) -> Result<(), BackendError>;
+
+    /// Jumps to `position` in the current track. Returns the length of the
+    /// track, if known.
+    async fn seek(
+        &self,
+        guild_id: GuildId,
+        position: Duration,
+    ) -> Result<Option<Duration>, BackendError>;
+
+    /// Changes the volume of what is playing. Saving it is up to the caller.
+    async fn set_volume(&self, guild_id: GuildId, volume: u16) -> Result<(), BackendError>;
+
+    async fn now_playing(&self, guild_id: GuildId) -> Result<Option<NowPlaying>, BackendError>;
+
+    /// How far into the current track playback is, if anything is playing.
+    async fn position(&self, guild_id: GuildId) -> Result<Option<Duration>, BackendError>;
+
+    async fn queue(&self, guild_id: GuildId) -> Result<QueueSnapshot, BackendError>;
+
+    async fn edit_queue(
+        &self,
+        guild_id: GuildId,
+        edit: QueueEdit,
+    ) -> Result<QueueEdited, BackendError>;
+
+    /// Sets what happens to songs once they finish, or moves on to the next
+    /// mode when `None`.
+    async fn set_loop_mode(
+        &self,
+        guild_id: GuildId,
+        mode: Option<LoopMode>,
+    ) -> Result<LoopMode, BackendError>;
+
+    /// Turns fair mode on or off, or toggles it when `None`.
+    async fn set_fair_scheduling(
+        &self,
+        guild_id: GuildId,
+        enabled: Option<bool>,
+    ) -> Result<bool, BackendError>;
+
+    /// Picks up the sessions of the given guilds saved before a restart.
+    async fn restore_sessions(&self, guild_ids: &[GuildId]);
+
+    /// Saves what can be restored on the next start. Meant to run right
+    /// before shutting down.
+    async fn suspend(&self);
+}
+
+pub struct MusicBackendContainer;
+
+impl TypeMapKey for MusicBackendContainer {
+    type Value = Arc<dyn MusicBackend>;
+}
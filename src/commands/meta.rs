@@ -3,7 +3,10 @@ use serenity::framework::standard::{macros::command, CommandResult};
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 
+use super::player::MusicState;
+use crate::backend::MusicBackendContainer;
 use crate::redis_store::RedisStore;
+use crate::util::format_duration;
 use crate::RedisClientContainer;
 
 #[command]
@@ -13,6 +16,35 @@ async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
     Ok(())
 }
 
+/// Shows which backend plays the music and how many guilds have a player.
+#[command]
+async fn status(ctx: &Context, msg: &Message) -> CommandResult {
+    let (backend, music_state) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<MusicBackendContainer>().cloned(),
+            data.get::<MusicState>().cloned(),
+        )
+    };
+    let status = match (backend, music_state) {
+        (Some(backend), Some(music_state)) => format!(
+            "{} guild player(s) running on the {} backend, idle ones shut down after {}",
+            backend.player_count().await,
+            backend.name(),
+            format_duration(music_state.idle_timeout)
+        ),
+        (Some(backend), None) => format!(
+            "{} guild player(s) running on the {} backend",
+            backend.player_count().await,
+            backend.name()
+        ),
+        (None, _) => "Music is not set up".to_string(),
+    };
+    msg.channel_id.say(&ctx.http, status).await?;
+
+    Ok(())
+}
+
 #[command]
 async fn prefix(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let data = ctx.data.read().await;
@@ -1,3 +1,4 @@
 pub mod meta;
 pub mod music;
 pub mod owner;
+pub mod player;
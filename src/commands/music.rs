@@ -1,21 +1,31 @@
-use dashmap::DashMap;
+use rand::seq::SliceRandom;
 use serenity::framework::standard::{macros::command, CommandResult};
 use serenity::framework::standard::{Args, CommandError};
 use serenity::http::Http;
 use serenity::model::prelude::*;
+use serenity::prelude::*;
 use serenity::Result as SerenityResult;
-use serenity::{async_trait, prelude::*};
-use songbird::input::Input;
-use songbird::tracks::TrackHandle;
-use songbird::{EventContext, EventHandler, Songbird, TrackEvent};
+use songbird::input::{Input, Restartable};
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::str::FromStr;
 use std::sync::Arc;
-
-#[derive(Debug, Clone)]
-pub enum PlayArgs {
-    SearchQuery(String),
-    YoutubeLink(String),
-}
+use std::time::Duration;
+
+use super::player::QueueEdit;
+use crate::backend::{
+    BackendError, EnqueueOutcome, Enqueued, MusicBackend, MusicBackendContainer, Playback,
+    TrackRequest,
+};
+use crate::redis_store::{PlayArgs, QueuedSong, RedisStore, RedisStoreError, TrackMetadata};
+use crate::util::{format_duration, format_eta, parse_duration, progress_bar};
+use crate::{RedisClientContainer, SearchProviderContainer};
+
+const QUEUE_PAGE_SIZE: usize = 10;
+const DEFAULT_VOLUME: u16 = 100;
+const MAX_VOLUME: u16 = 200;
+const SEARCH_RESULTS: usize = 5;
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(30);
 
 impl From<Args> for PlayArgs {
     fn from(mut args: Args) -> Self {
@@ -43,119 +53,181 @@ impl From<Args> for PlayArgs {
     }
 }
 
-impl std::fmt::Display for PlayArgs {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            PlayArgs::SearchQuery(query) => write!(f, "{}", query),
-            PlayArgs::YoutubeLink(link) => write!(f, "{}", link),
+impl QueuedSong {
+    /// A song known only by what was asked for, until its audio is resolved.
+    pub fn from_play_args(play: PlayArgs, channel_id: ChannelId, requester: UserId) -> Self {
+        QueuedSong {
+            channel_id,
+            name: play.to_string(),
+            play,
+            requester,
+            duration: None,
         }
     }
-}
 
-#[derive(Debug)]
-pub struct QueuedSong {
-    channel_id: ChannelId,
-    name: String,
-    play: PlayArgs,
+    pub fn from_track(track: &TrackMetadata, channel_id: ChannelId, requester: UserId) -> Self {
+        QueuedSong {
+            channel_id,
+            name: track.title.clone(),
+            play: PlayArgs::YoutubeLink(track.url.clone()),
+            requester,
+            duration: track.duration,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum PlayingStatus {
-    Playing { name: String },
-    Paused { name: String },
-    Stopped,
+impl TrackMetadata {
+    pub fn from_input(input: &Input) -> Option<Self> {
+        Some(TrackMetadata {
+            title: input.metadata.title.clone()?,
+            url: input.metadata.source_url.clone()?,
+            duration: input.metadata.duration,
+            thumbnail: input.metadata.thumbnail.clone(),
+            uploader: input.metadata.artist.clone(),
+        })
+    }
 }
 
-impl Default for PlayingStatus {
-    fn default() -> Self {
-        PlayingStatus::Stopped
+/// The songs waiting to be played in a guild, in play order. This is a
+/// working copy, the queue itself lives in Redis.
+#[derive(Debug, Default)]
+pub struct SongQueue(VecDeque<QueuedSong>);
+
+impl From<Vec<QueuedSong>> for SongQueue {
+    fn from(songs: Vec<QueuedSong>) -> Self {
+        SongQueue(songs.into())
     }
 }
 
-#[derive(Debug)]
-pub struct GuildMusicState {
-    guild_id: GuildId,
-    queue: Vec<QueuedSong>,
-    playing_status: PlayingStatus,
-    handle: Option<TrackHandle>,
-    manager: Arc<Songbird>,
-}
+impl SongQueue {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 
-#[derive(Debug, Clone, Default)]
-pub struct MusicState {
-    pub guild_states: DashMap<GuildId, Arc<RwLock<GuildMusicState>>>,
-}
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 
-impl TypeMapKey for MusicState {
-    type Value = MusicState;
-}
+    pub fn iter(&self) -> std::collections::vec_deque::Iter<'_, QueuedSong> {
+        self.0.iter()
+    }
 
-pub struct SongFinishedEventHandler(pub Arc<RwLock<GuildMusicState>>);
+    pub fn get(&self, index: usize) -> Option<&QueuedSong> {
+        self.0.get(index)
+    }
 
-#[async_trait]
-impl EventHandler for SongFinishedEventHandler {
-    async fn act(&self, _: &EventContext<'_>) -> Option<songbird::Event> {
-        let mut state = self.0.write().await;
+    pub fn push_back(&mut self, song: QueuedSong) {
+        self.0.push_back(song);
+    }
 
-        let next = state.queue.pop();
+    pub fn pop_front(&mut self) -> Option<QueuedSong> {
+        self.0.pop_front()
+    }
 
-        if let Some(next_song) = next {
-            if let Some(handler_lock) = state.manager.get(state.guild_id) {
-                let mut handler = handler_lock.lock().await;
-                handler.stop();
-                let input = match input_from_yt_url(&next_song.play, next_song.channel_id).await {
-                    Ok(input) => input,
-                    Err(why) => {
-                        eprintln!("Error: {:?}", why);
-                        return None;
-                    }
-                };
-                let _ = send_msg(
-                    next_song.channel_id,
-                    &format!(
-                        "Now playing {}(<{}>), {} tracks in queue",
-                        input.metadata.title.as_deref().unwrap_or("-"),
-                        input.metadata.source_url.as_deref().unwrap_or("-"),
-                        state.queue.len()
-                    ),
-                )
-                .await;
-                state.playing_status = PlayingStatus::Playing {
-                    name: next_song.name,
-                };
-                let handle = handler.play_source(input);
-                handle
-                    .add_event(
-                        songbird::Event::Track(TrackEvent::End),
-                        SongFinishedEventHandler(self.0.clone()),
-                    )
-                    .map_err(|e| eprintln!("Failed to add event : {e}"))
-                    .ok();
-                state.handle = Some(handle);
+    pub fn remove(&mut self, index: usize) -> Option<QueuedSong> {
+        self.0.remove(index)
+    }
+
+    /// Moves the song at `from` so that it ends up at index `to`.
+    pub fn move_song(&mut self, from: usize, to: usize) -> Option<&QueuedSong> {
+        if to >= self.0.len() {
+            return None;
+        }
+        let song = self.0.remove(from)?;
+        self.0.insert(to, song);
+        self.0.get(to)
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) -> bool {
+        if a >= self.0.len() || b >= self.0.len() {
+            return false;
+        }
+        self.0.swap(a, b);
+        true
+    }
+
+    pub fn shuffle(&mut self) {
+        self.0.make_contiguous().shuffle(&mut rand::thread_rng());
+    }
+
+    /// Reorders the queue round-robin by requester, keeping each requester's
+    /// songs in their current order. The requester of the song that is playing
+    /// right now, if any, goes last in the rotation.
+    pub fn interleave_by_requester(&mut self, current_requester: Option<UserId>) {
+        let mut buckets: Vec<(UserId, VecDeque<QueuedSong>)> = Vec::new();
+        for song in self.0.drain(..) {
+            match buckets.iter_mut().find(|(id, _)| *id == song.requester) {
+                Some((_, songs)) => songs.push_back(song),
+                None => buckets.push((song.requester, VecDeque::from(vec![song]))),
             }
-        } else {
-            state.playing_status = PlayingStatus::Stopped;
         }
 
-        None
+        if let Some(current) = current_requester {
+            if let Some(index) = buckets.iter().position(|(id, _)| *id == current) {
+                let bucket = buckets.remove(index);
+                buckets.push(bucket);
+            }
+        }
+
+        while !buckets.is_empty() {
+            for (_, songs) in buckets.iter_mut() {
+                if let Some(song) = songs.pop_front() {
+                    self.0.push_back(song);
+                }
+            }
+            buckets.retain(|(_, songs)| !songs.is_empty());
+        }
     }
 }
 
-pub struct SongStartEventHandler(pub Arc<RwLock<GuildMusicState>>);
+/// What happens to a song once it finishes playing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopMode {
+    Off,
+    /// Replay the same song until the mode changes or it gets skipped.
+    Track,
+    /// Put finished songs back at the end of the queue.
+    Queue,
+}
 
-#[async_trait]
-impl EventHandler for SongStartEventHandler {
-    async fn act(&self, _: &EventContext<'_>) -> Option<songbird::Event> {
-        None
+// `#[default]` on the variant needs a newer compiler than the one we build with.
+#[allow(clippy::derivable_impls)]
+impl Default for LoopMode {
+    fn default() -> Self {
+        LoopMode::Off
+    }
+}
+
+impl LoopMode {
+    pub fn next(self) -> Self {
+        match self {
+            LoopMode::Off => LoopMode::Track,
+            LoopMode::Track => LoopMode::Queue,
+            LoopMode::Queue => LoopMode::Off,
+        }
     }
 }
 
-pub struct SongPauseEventHandler(pub Arc<RwLock<GuildMusicState>>);
+impl std::fmt::Display for LoopMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoopMode::Off => write!(f, "off"),
+            LoopMode::Track => write!(f, "track"),
+            LoopMode::Queue => write!(f, "queue"),
+        }
+    }
+}
 
-#[async_trait]
-impl EventHandler for SongPauseEventHandler {
-    async fn act(&self, _: &EventContext<'_>) -> Option<songbird::Event> {
-        None
+impl FromStr for LoopMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" | "none" => Ok(LoopMode::Off),
+            "track" | "song" | "one" => Ok(LoopMode::Track),
+            "queue" | "all" => Ok(LoopMode::Queue),
+            _ => Err(format!("Unknown loop mode: {s}")),
+        }
     }
 }
 
@@ -174,25 +246,55 @@ async fn joinchan(ctx: &Context, msg: &Message) -> CommandResult {
         .and_then(|state| state.channel_id)
         .ok_or_else(|| CommandError::from("No channel found."))?;
 
-    let guild = msg.guild(&ctx.cache).await.unwrap();
-    let guild_id = guild.id;
-
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    let _ = manager.join(guild_id, channel_id).await;
+    match backend(ctx).await.join(guild.id, channel_id).await {
+        Ok(()) => check_msg(
+            msg.channel_id
+                .say(&ctx.http, format!("Joined {}", channel_id.mention()))
+                .await,
+        ),
+        Err(why) => check_msg(
+            msg.channel_id
+                .say(&ctx.http, format!("Error joining the channel: {}", why))
+                .await,
+        ),
+    }
 
     Ok(())
 }
 
-// TODO: Show song length and queue length (time remaining)
-// TODO: Move queue and playing current state to redis. If some state is kept, switch from Mutex to RwLock
-// TODO: Possibly use youtube api to make it more efficient to search
-// TODO: Cache song metadata in redis
+// TODO: Move playing current state to redis. If some state is kept, switch from Mutex to RwLock
 #[command]
 async fn play(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    play_song(ctx, msg, args, false).await
+}
+
+#[command]
+async fn playnext(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    play_song(ctx, msg, args, true).await
+}
+
+async fn play_song(ctx: &Context, msg: &Message, args: Args, next: bool) -> CommandResult {
+    if args.is_empty() {
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, "No video or audio provided")
+                .await,
+        );
+
+        return Ok(());
+    }
+
+    play_or_enqueue(ctx, msg, PlayArgs::from(args), next).await
+}
+
+/// Starts playing right away when idle, otherwise queues the song at the back
+/// of the queue, or at the front when `next` is set.
+async fn play_or_enqueue(
+    ctx: &Context,
+    msg: &Message,
+    play_arg: PlayArgs,
+    next: bool,
+) -> CommandResult {
     let user_id = msg.author.id;
 
     let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
@@ -203,381 +305,815 @@ async fn play(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
         .and_then(|state| state.channel_id)
         .ok_or_else(|| CommandError::from("No channel found."))?;
 
-    if args.is_empty() {
+    let request = TrackRequest {
+        play: play_arg,
+        voice_channel_id: channel_id,
+        text_channel_id: msg.channel_id,
+        requester: user_id,
+        next,
+    };
+    match backend(ctx).await.enqueue(guild.id, request).await {
+        // The backend lets the channel know once it plays.
+        Ok(EnqueueOutcome::Started) => {}
+        Ok(EnqueueOutcome::Queued { name, queued }) => check_msg(
+            msg.channel_id
+                .say(&ctx.http, queued_reply(&name, next, &queued))
+                .await,
+        ),
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    }
+
+    Ok(())
+}
+
+#[command]
+async fn stop(ctx: &Context, msg: &Message) -> CommandResult {
+    leave_voice(ctx, msg).await
+}
+
+#[command]
+async fn skip(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let n = if !args.is_empty() {
+        match args.single::<usize>() {
+            Ok(url) => url,
+            Err(_) => {
+                check_msg(
+                    msg.channel_id
+                        .say(&ctx.http, "Expected a positive whole number")
+                        .await,
+                );
+                return Ok(());
+            }
+        }
+    } else {
+        1
+    };
+
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let guild_id = guild.id;
+    let queue_len = match backend(ctx).await.skip(guild_id, n).await {
+        Ok(queue_len) => queue_len,
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+
+    if queue_len > 0 {
         check_msg(
             msg.channel_id
-                .say(&ctx.http, "No video or audio provided")
+                .say(
+                    &ctx.http,
+                    format!("Skipping {n} ({} tracks in queue)", queue_len - 1),
+                )
+                .await,
+        );
+    } else {
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, "Skipping, no more tracks to play")
                 .await,
         );
-
-        return Ok(());
     }
 
-    let play_arg = PlayArgs::from(args);
+    Ok(())
+}
 
+#[command]
+async fn pause(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
     let guild_id = guild.id;
+    if let Err(why) = backend(ctx).await.pause(guild_id).await {
+        return reply_rejection(ctx, msg, why).await;
+    }
+    check_msg(msg.channel_id.say(&ctx.http, "Pausing").await);
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
-
-    let _ = manager.join(guild_id, channel_id).await;
+    Ok(())
+}
 
-    let mut ctx_data = ctx.data.write().await;
-    let music_states = if let Some(music_states) = ctx_data.get_mut::<MusicState>() {
-        music_states
+#[command]
+async fn queue(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let page = if !args.is_empty() {
+        match args.single::<usize>() {
+            Ok(page) if page > 0 => page,
+            _ => {
+                check_msg(
+                    msg.channel_id
+                        .say(&ctx.http, "Expected a page number")
+                        .await,
+                );
+                return Ok(());
+            }
+        }
     } else {
-        ctx_data.insert::<MusicState>(MusicState::default());
-        ctx_data
-            .get_mut::<MusicState>()
-            .expect("MusicState not found")
+        1
     };
 
-    let handler_lock = if let Some(handler_lock) = manager.get(guild_id) {
-        handler_lock
-    } else {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let guild_id = guild.id;
+    let snapshot = match backend(ctx).await.queue(guild_id).await {
+        Ok(snapshot) => snapshot,
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+    let queue = &snapshot.songs;
+
+    let current = snapshot
+        .current
+        .as_ref()
+        .map(|(song, playback)| match playback {
+            Playback::Playing => (song, "Now playing"),
+            Playback::Paused => (song, "Paused"),
+            Playback::Starting => (song, "Starting"),
+        });
+    if current.is_none() && queue.is_empty() {
         check_msg(
             msg.channel_id
-                .say(&ctx.http, "Not in a voice channel to play in")
+                .say(&ctx.http, "Nothing is playing and the queue is empty")
                 .await,
         );
+        return Ok(());
+    }
 
+    let pages = ((queue.len() + QUEUE_PAGE_SIZE - 1) / QUEUE_PAGE_SIZE).max(1);
+    if page > pages {
+        check_msg(
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!("There is no page {page}, the queue has {pages} page(s)"),
+                )
+                .await,
+        );
         return Ok(());
-    };
+    }
 
-    if let Some(music_state_mutex) = music_states.guild_states.get_mut(&guild_id) {
-        let mut state = music_state_mutex.write().await;
-        let mut handler = handler_lock.lock().await;
+    let mut description = String::new();
+    let mut remaining = Duration::ZERO;
+    let mut unknown_durations = false;
 
-        if let PlayingStatus::Playing { .. } = state.playing_status {
-            let input = match input_from_yt_url(&play_arg, channel_id).await {
-                Ok(input) => input,
-                Err(why) => {
-                    let _ = send_msg(channel_id, &format!("Error: {:?}", why)).await;
-                    eprintln!("Error: {:?}", why);
-                    return Ok(());
-                }
+    if let Some((song, label)) = current {
+        let elapsed = snapshot.elapsed;
+        match song.duration {
+            Some(duration) => remaining += duration.saturating_sub(elapsed),
+            None => unknown_durations = true,
+        }
+        description.push_str(&format!(
+            "**{label}:** {} `{} / {}` {}\n\n",
+            song.name,
+            format_duration(elapsed),
+            song.duration
+                .map(format_duration)
+                .unwrap_or_else(|| "?".to_string()),
+            song.requester.mention()
+        ));
+    }
+
+    let shown = (page - 1) * QUEUE_PAGE_SIZE..page * QUEUE_PAGE_SIZE;
+    for (position, song) in queue.iter().enumerate() {
+        if shown.contains(&position) {
+            // Looping a single track means the queue never moves on.
+            let starts_in = if snapshot.loop_mode == LoopMode::Track {
+                None
+            } else {
+                Some((remaining, unknown_durations))
             };
+            description.push_str(&queue_entry_line(position + 1, song, starts_in));
+        }
+        match song.duration {
+            Some(duration) => remaining += duration,
+            None => unknown_durations = true,
+        }
+    }
+    if queue.is_empty() {
+        description.push_str("No tracks in queue");
+    }
+
+    let mut footer = format!(
+        "Page {page}/{pages} | {} tracks in queue | {}{} remaining | Loop: {}",
+        queue.len(),
+        format_duration(remaining),
+        if unknown_durations { "+" } else { "" },
+        snapshot.loop_mode
+    );
+    if snapshot.fair_scheduling {
+        footer.push_str(" | Fair");
+    }
+    if pages > 1 {
+        footer.push_str(" | queue <page> to see more");
+    }
+
+    check_msg(
+        msg.channel_id
+            .send_message(&ctx.http, |m| {
+                m.embed(|e| {
+                    e.title("Queue")
+                        .description(description)
+                        .footer(|f| f.text(footer))
+                })
+            })
+            .await,
+    );
+
+    Ok(())
+}
+
+#[command]
+#[aliases(np, nowplaying)]
+async fn now_playing(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let playing = match backend(ctx).await.now_playing(guild.id).await {
+        Ok(Some(playing)) => playing,
+        Ok(None) => {
             check_msg(
                 msg.channel_id
-                    .say(
-                        &ctx.http,
-                        format!(
-                            "Queing {} (<{}>), {} tracks in queue",
-                            input.metadata.title.as_deref().unwrap_or("-"),
-                            input.metadata.source_url.as_deref().unwrap_or("-"),
-                            state.queue.len() + 1
-                        ),
-                    )
+                    .say(&ctx.http, "Nothing is playing at the moment")
                     .await,
             );
-            state.queue.push(QueuedSong {
-                name: play_arg.to_string(),
-                play: play_arg,
-                channel_id: msg.channel_id,
-            });
-        } else {
-            let input = match input_from_yt_url(&play_arg, msg.channel_id).await {
-                Ok(input) => input,
-                Err(why) => {
-                    let _ = send_msg(channel_id, &format!("Error: {:?}", why)).await;
-                    eprintln!("Error: {:?}", why);
-                    return Ok(());
-                }
-            };
-            check_msg(
-                msg.channel_id
-                    .say(
-                        &ctx.http,
-                        format!(
-                            "Playing {} (<{}>)",
-                            input.metadata.title.as_deref().unwrap_or("-"),
-                            input.metadata.source_url.as_deref().unwrap_or("-")
-                        ),
+            return Ok(());
+        }
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+
+    let elapsed = playing.elapsed;
+    let mut description = match (playing.duration, playing.live) {
+        (_, true) => format!("`{}` (live)", format_duration(elapsed)),
+        (Some(duration), false) => format!(
+            "{}\n`{} / {}`",
+            progress_bar(elapsed, duration),
+            format_duration(elapsed),
+            format_duration(duration)
+        ),
+        (None, false) => format!("`{} / ?`", format_duration(elapsed)),
+    };
+    if playing.paused {
+        description.push_str(" (paused)");
+    }
+
+    check_msg(
+        msg.channel_id
+            .send_message(&ctx.http, |m| {
+                m.embed(|e| {
+                    e.title(&playing.title).description(description);
+                    if let Some(url) = &playing.url {
+                        e.url(url);
+                    }
+                    if let Some(thumbnail) = &playing.thumbnail {
+                        e.thumbnail(thumbnail);
+                    }
+                    if let Some(requester) = playing.requester {
+                        e.field("Requested by", requester.mention(), true);
+                    }
+                    if let Some(loop_mode) = playing.loop_mode {
+                        e.field("Loop", loop_mode, true);
+                    }
+                    e.field(
+                        "Next up",
+                        playing.next_up.as_deref().unwrap_or("Nothing"),
+                        false,
                     )
-                    .await,
-            );
-            state.playing_status = PlayingStatus::Playing {
-                name: play_arg.to_string(),
-            };
-            let handle = handler.play_source(input);
-            handle
-                .add_event(
-                    songbird::Event::Track(TrackEvent::End),
-                    SongFinishedEventHandler(music_state_mutex.clone()),
-                )
-                .map_err(|e| CommandError::from(format!("Failed to add event : {e}")))?;
-            state.handle = Some(handle);
-            state.manager = manager;
+                })
+            })
+            .await,
+    );
+
+    Ok(())
+}
+
+#[command]
+async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let index = match parse_queue_position(&mut args) {
+        Ok(index) => index,
+        Err(why) => {
+            check_msg(msg.channel_id.say(&ctx.http, why).await);
+            return Ok(());
         }
+    };
 
-        let _ = &ctx;
-    } else {
-        let input = match input_from_yt_url(&play_arg, msg.channel_id).await {
-            Ok(input) => input,
-            Err(why) => {
-                eprintln!("Error: {:?}", why);
-                return Ok(());
-            }
-        };
-        let mut handler = handler_lock.lock().await;
-        let queue = Vec::new();
+    let edited = match backend(ctx)
+        .await
+        .edit_queue(guild.id, QueueEdit::Remove(index))
+        .await
+    {
+        Ok(edited) => edited,
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+    if let Some(song) = edited.songs.first() {
         check_msg(
             msg.channel_id
                 .say(
                     &ctx.http,
                     format!(
-                        "Playing {} (<{}>)",
-                        input.metadata.title.as_deref().unwrap_or("-"),
-                        input.metadata.source_url.as_deref().unwrap_or("-")
+                        "Removed `{}.` {}, {} tracks in queue",
+                        index + 1,
+                        song.name,
+                        edited.queue_len
                     ),
                 )
                 .await,
         );
-        let handle = handler.play_source(input);
-        let music_state_mutex = Arc::new(RwLock::new(GuildMusicState {
-            guild_id,
-            queue,
-            playing_status: PlayingStatus::Playing {
-                name: play_arg.to_string(),
-            },
-            handle: None,
-            manager,
-        }));
-        handle
-            .add_event(
-                songbird::Event::Track(TrackEvent::End),
-                SongFinishedEventHandler(music_state_mutex.clone()),
-            )
-            .map_err(|e| CommandError::from(format!("Failed to add event : {e}")))?;
-        music_state_mutex.write().await.handle = Some(handle);
-        music_states
-            .guild_states
-            .insert(guild_id, music_state_mutex.clone());
+    }
+
+    Ok(())
+}
+
+#[command("move")]
+async fn move_song(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let positions = parse_queue_position(&mut args)
+        .and_then(|from| parse_queue_position(&mut args).map(|to| (from, to)));
+    let (from, to) = match positions {
+        Ok(positions) => positions,
+        Err(why) => {
+            check_msg(msg.channel_id.say(&ctx.http, why).await);
+            return Ok(());
+        }
+    };
+
+    let edited = match backend(ctx)
+        .await
+        .edit_queue(guild.id, QueueEdit::Move { from, to })
+        .await
+    {
+        Ok(edited) => edited,
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+    if let Some(song) = edited.songs.first() {
+        check_msg(
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!("Moved {} to position {}", song.name, to + 1),
+                )
+                .await,
+        );
     }
 
     Ok(())
 }
 
 #[command]
-async fn stop(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).await.unwrap();
-    let guild_id = guild.id;
+async fn swap(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let positions = parse_queue_position(&mut args)
+        .and_then(|a| parse_queue_position(&mut args).map(|b| (a, b)));
+    let (a, b) = match positions {
+        Ok(positions) => positions,
+        Err(why) => {
+            check_msg(msg.channel_id.say(&ctx.http, why).await);
+            return Ok(());
+        }
+    };
 
-    let manager = songbird::get(ctx)
+    let edited = match backend(ctx)
         .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+        .edit_queue(guild.id, QueueEdit::Swap(a, b))
+        .await
+    {
+        Ok(edited) => edited,
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+    let first = edited
+        .songs
+        .get(0)
+        .map(|song| song.name.as_str())
+        .unwrap_or("-");
+    let second = edited
+        .songs
+        .get(1)
+        .map(|song| song.name.as_str())
+        .unwrap_or("-");
+    check_msg(
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "Swapped positions {} and {}, now `{}.` {} and `{}.` {}",
+                    a + 1,
+                    b + 1,
+                    a + 1,
+                    first,
+                    b + 1,
+                    second
+                ),
+            )
+            .await,
+    );
 
-    let _ = manager.leave(guild_id).await;
+    Ok(())
+}
+
+#[command]
+async fn clear(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let cleared = match backend(ctx)
+        .await
+        .edit_queue(guild.id, QueueEdit::Clear)
+        .await
+    {
+        Ok(edited) => edited.songs.len(),
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+
+    check_msg(
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!("Cleared {cleared} tracks from the queue"),
+            )
+            .await,
+    );
 
     Ok(())
 }
 
 #[command]
-async fn skip(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let n = if !args.is_empty() {
-        match args.single::<usize>() {
-            Ok(url) => url,
-            Err(_) => {
+async fn shuffle(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let edited = match backend(ctx)
+        .await
+        .edit_queue(guild.id, QueueEdit::Shuffle)
+        .await
+    {
+        Ok(edited) => edited,
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+
+    check_msg(
+        msg.channel_id
+            .say(&ctx.http, format!("Shuffled {} tracks", edited.queue_len))
+            .await,
+    );
+
+    Ok(())
+}
+
+#[command]
+async fn fair(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let requested = if !args.is_empty() {
+        match args.single::<String>().as_deref() {
+            Ok("on") => Some(true),
+            Ok("off") => Some(false),
+            _ => {
                 check_msg(
                     msg.channel_id
-                        .say(&ctx.http, "Expected a positive whole number")
+                        .say(&ctx.http, "Expected `on` or `off`")
                         .await,
                 );
                 return Ok(());
             }
         }
     } else {
-        1
+        None
     };
 
     let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
-    let guild_id = guild.id;
-    let mut ctx_data = ctx.data.write().await;
-    let music_states = if let Some(music_states) = ctx_data.get_mut::<MusicState>() {
-        music_states
-    } else {
-        ctx_data.insert::<MusicState>(MusicState::default());
-        ctx_data
-            .get_mut::<MusicState>()
-            .expect("MusicState not found")
-    };
-    let queue_len = if let Some(music_state_mutex) = music_states.guild_states.get_mut(&guild_id) {
-        let mut state = music_state_mutex.write().await;
-
-        if n > 1 {
-            for _ in 0..n - 1 {
-                let _ = state.queue.pop();
-            }
-        }
-
-        if let Some(handle) = &state.handle {
-            handle
-                .stop()
-                .map_err(|e| CommandError::from(format!("Failed to pause : {e}")))?;
-        }
-        state.queue.len()
-    } else {
-        return Ok(());
+    let fair_scheduling = match backend(ctx)
+        .await
+        .set_fair_scheduling(guild.id, requested)
+        .await
+    {
+        Ok(fair_scheduling) => fair_scheduling,
+        Err(why) => return reply_rejection(ctx, msg, why).await,
     };
-
-    if queue_len > 0 {
+    if fair_scheduling {
         check_msg(
             msg.channel_id
                 .say(
                     &ctx.http,
-                    format!("Skipping {n} ({} tracks in queue)", queue_len - 1),
+                    "Fair mode on, the queue now takes turns between requesters",
                 )
                 .await,
         );
     } else {
-        check_msg(
-            msg.channel_id
-                .say(&ctx.http, "Skipping, no more tracks to play")
-                .await,
-        );
+        check_msg(msg.channel_id.say(&ctx.http, "Fair mode off").await);
     }
 
     Ok(())
 }
 
 #[command]
-async fn pause(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
-    let guild_id = guild.id;
-    let mut ctx_data = ctx.data.write().await;
-    let music_states = if let Some(music_states) = ctx_data.get_mut::<MusicState>() {
-        music_states
-    } else {
-        ctx_data.insert::<MusicState>(MusicState::default());
-        ctx_data
-            .get_mut::<MusicState>()
-            .expect("MusicState not found")
-    };
-    if let Some(music_state_mutex) = music_states.guild_states.get_mut(&guild_id) {
-        let mut state = music_state_mutex.write().await;
-        if let PlayingStatus::Playing { name } = &state.playing_status {
-            state.playing_status = PlayingStatus::Paused { name: name.clone() };
-            check_msg(msg.channel_id.say(&ctx.http, "Pausing").await);
-        } else {
+async fn seek(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    match args
+        .single::<String>()
+        .ok()
+        .as_deref()
+        .and_then(parse_duration)
+    {
+        Some(position) => seek_current(ctx, msg, SeekTarget::To(position)).await,
+        None => {
             check_msg(
                 msg.channel_id
-                    .say(&ctx.http, "Not playing so can't pause")
+                    .say(&ctx.http, "Expected a timestamp, like `1:23`")
                     .await,
             );
-        }
-        if let Some(handle) = &state.handle {
-            handle
-                .pause()
-                .map_err(|e| CommandError::from(format!("Failed to pause : {e}")))?;
+            Ok(())
         }
     }
-
-    Ok(())
 }
 
 #[command]
-async fn queue(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
-    let guild_id = guild.id;
-    let mut ctx_data = ctx.data.write().await;
-    let music_states = if let Some(music_states) = ctx_data.get_mut::<MusicState>() {
-        music_states
-    } else {
-        ctx_data.insert::<MusicState>(MusicState::default());
-        ctx_data
-            .get_mut::<MusicState>()
-            .expect("MusicState not found")
-    };
-    if let Some(music_state_mutex) = music_states.guild_states.get_mut(&guild_id) {
-        let mut state = music_state_mutex.write().await;
-        if let PlayingStatus::Playing { name } = &state.playing_status {
-            state.playing_status = PlayingStatus::Paused { name: name.clone() };
-            check_msg(msg.channel_id.say(&ctx.http, "Pausing").await);
-        } else {
+#[aliases(fastforward)]
+async fn ff(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    match parse_offset(&mut args) {
+        Some(offset) => seek_current(ctx, msg, SeekTarget::Forward(offset)).await,
+        None => {
             check_msg(
                 msg.channel_id
-                    .say(&ctx.http, "Not playing so can't pause")
+                    .say(&ctx.http, "Expected an amount of time, like `30s`")
                     .await,
             );
-        }
-        if let Some(handle) = &state.handle {
-            handle
-                .pause()
-                .map_err(|e| CommandError::from(format!("Failed to pause : {e}")))?;
+            Ok(())
         }
     }
+}
 
-    Ok(())
+#[command]
+async fn rewind(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    match parse_offset(&mut args) {
+        Some(offset) => seek_current(ctx, msg, SeekTarget::Back(offset)).await,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Expected an amount of time, like `15s`")
+                    .await,
+            );
+            Ok(())
+        }
+    }
 }
 
 #[command]
-async fn unpause(ctx: &Context, msg: &Message) -> CommandResult {
+#[aliases(vol)]
+async fn volume(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
     let guild_id = guild.id;
-    let mut ctx_data = ctx.data.write().await;
-    let music_states = if let Some(music_states) = ctx_data.get_mut::<MusicState>() {
-        music_states
-    } else {
-        ctx_data.insert::<MusicState>(MusicState::default());
-        ctx_data
-            .get_mut::<MusicState>()
-            .expect("MusicState not found")
-    };
-    if let Some(music_state_mutex) = music_states.guild_states.get_mut(&guild_id) {
-        let mut state = music_state_mutex.write().await;
-        if let PlayingStatus::Paused { name } = &state.playing_status {
-            state.playing_status = PlayingStatus::Playing { name: name.clone() };
-            check_msg(msg.channel_id.say(&ctx.http, "Unpausing").await);
-        } else {
+    let redis_client = ctx.data.read().await.get::<RedisClientContainer>().cloned();
+
+    if args.is_empty() {
+        let volume = match &redis_client {
+            Some(redis_client) => stored_volume(redis_client, guild_id).await,
+            None => DEFAULT_VOLUME,
+        };
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, format!("Volume is {volume}%"))
+                .await,
+        );
+        return Ok(());
+    }
+
+    let volume = match args.single::<u16>() {
+        Ok(volume) if volume <= MAX_VOLUME => volume,
+        _ => {
             check_msg(
                 msg.channel_id
-                    .say(&ctx.http, "Not paused so can't unpause")
+                    .say(
+                        &ctx.http,
+                        format!("Expected a volume between 0 and {MAX_VOLUME}"),
+                    )
                     .await,
             );
+            return Ok(());
         }
-        if let Some(handle) = &state.handle {
-            handle
-                .play()
-                .map_err(|e| CommandError::from(format!("Failed to pause : {e}")))?;
-        }
+    };
+
+    if let Err(why) = backend(ctx).await.set_volume(guild_id, volume).await {
+        return reply_rejection(ctx, msg, why).await;
+    }
+
+    let saved = match redis_client {
+        Some(redis_client) => match redis_client.get_async_connection().await {
+            Ok(conn) => RedisStore::new(conn)
+                .set_volume(guild_id, volume)
+                .await
+                .map_err(|e| eprintln!("Failed to set volume : {:?}", e))
+                .is_ok(),
+            Err(e) => {
+                eprintln!("Failed to get Redis connection : {}", e);
+                false
+            }
+        },
+        None => false,
+    };
+
+    if saved {
+        check_msg(
+            msg.channel_id
+                .say(&ctx.http, format!("Volume set to {volume}%"))
+                .await,
+        );
+    } else {
+        check_msg(
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!("Volume set to {volume}%, but it could not be saved"),
+                )
+                .await,
+        );
     }
 
     Ok(())
 }
 
-#[command]
-async fn quit(ctx: &Context, msg: &Message, _: Args) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).await.unwrap();
-    let guild_id = guild.id;
+#[command("loop")]
+async fn loop_mode(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let requested = if !args.is_empty() {
+        match args.single::<LoopMode>() {
+            Ok(mode) => Some(mode),
+            Err(_) => {
+                check_msg(
+                    msg.channel_id
+                        .say(&ctx.http, "Expected one of `off`, `track` or `queue`")
+                        .await,
+                );
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let loop_mode = match backend(ctx).await.set_loop_mode(guild.id, requested).await {
+        Ok(loop_mode) => loop_mode,
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
 
-    let _ = manager.leave(guild_id).await;
+    check_msg(
+        msg.channel_id
+            .say(&ctx.http, format!("Loop mode set to {loop_mode}"))
+            .await,
+    );
 
     Ok(())
 }
 
 #[command]
-async fn search(ctx: &Context, msg: &Message, _: Args) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).await.unwrap();
+async fn unpause(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
     let guild_id = guild.id;
+    if let Err(why) = backend(ctx).await.resume(guild_id).await {
+        return reply_rejection(ctx, msg, why).await;
+    }
+    check_msg(msg.channel_id.say(&ctx.http, "Unpausing").await);
 
-    let manager = songbird::get(ctx)
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    Ok(())
+}
 
-    let _ = manager.leave(guild_id).await;
+#[command]
+async fn quit(ctx: &Context, msg: &Message, _: Args) -> CommandResult {
+    leave_voice(ctx, msg).await
+}
+
+async fn leave_voice(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    if let Err(why) = backend(ctx).await.leave(guild.id).await {
+        return reply_rejection(ctx, msg, why).await;
+    }
+    check_msg(msg.channel_id.say(&ctx.http, "Left voice channel").await);
 
     Ok(())
 }
 
+/// Lists the top results for a query and queues the one the user picks by
+/// replying with its number.
+#[command]
+async fn search(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    if args.is_empty() {
+        check_msg(msg.channel_id.say(&ctx.http, "Nothing to search for").await);
+        return Ok(());
+    }
+    let query = args.rest().to_string();
+
+    let (redis_client, search_provider) = {
+        let data = ctx.data.read().await;
+        (
+            data.get::<RedisClientContainer>().cloned(),
+            data.get::<SearchProviderContainer>().cloned(),
+        )
+    };
+    let search_provider = match search_provider {
+        Some(search_provider) => search_provider,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "Search provider not found")
+                    .await,
+            );
+            return Ok(());
+        }
+    };
+    let mut track_cache = match &redis_client {
+        Some(redis_client) => match redis_client.get_async_connection().await {
+            Ok(conn) => Some(RedisStore::new(conn)),
+            Err(e) => {
+                eprintln!("Failed to get Redis connection : {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let cached = match &mut track_cache {
+        Some(track_cache) => track_cache
+            .get_cached_search(&query)
+            .await
+            .map_err(|why| eprintln!("Failed to read the track cache : {:?}", why))
+            .ok()
+            .flatten(),
+        None => None,
+    };
+
+    let results = match cached {
+        Some(results) => results,
+        None => match search_provider.search(&query, SEARCH_RESULTS).await {
+            Ok(results) => {
+                if let Some(track_cache) = &mut track_cache {
+                    if let Err(why) = track_cache.cache_search(&query, &results).await {
+                        eprintln!("Failed to cache the search : {:?}", why);
+                    }
+                }
+                results
+            }
+            Err(why) => {
+                eprintln!("Failed to search : {:?}", why);
+                check_msg(
+                    msg.channel_id
+                        .say(&ctx.http, format!("Failed to search : {why}"))
+                        .await,
+                );
+                return Ok(());
+            }
+        },
+    };
+    if results.is_empty() {
+        check_msg(msg.channel_id.say(&ctx.http, "No results found").await);
+        return Ok(());
+    }
+
+    let description: String = results
+        .iter()
+        .enumerate()
+        .map(|(index, result)| {
+            format!(
+                "`{}.` {} - {} `{}`\n",
+                index + 1,
+                result.title,
+                result.uploader.as_deref().unwrap_or("-"),
+                result
+                    .duration
+                    .map(format_duration)
+                    .unwrap_or_else(|| "?".to_string())
+            )
+        })
+        .collect();
+    check_msg(
+        msg.channel_id
+            .send_message(&ctx.http, |m| {
+                m.embed(|e| {
+                    e.title(format!("Results for {query}"))
+                        .description(description)
+                        .footer(|f| {
+                            f.text(format!(
+                                "Reply with a number within {} seconds to queue it, anything else cancels",
+                                SEARCH_TIMEOUT.as_secs()
+                            ))
+                        })
+                })
+            })
+            .await,
+    );
+
+    let reply = match msg
+        .channel_id
+        .await_reply(ctx)
+        .author_id(msg.author.id)
+        .timeout(SEARCH_TIMEOUT)
+        .await
+    {
+        Some(reply) => reply,
+        None => {
+            check_msg(msg.channel_id.say(&ctx.http, "Search timed out").await);
+            return Ok(());
+        }
+    };
+    let picked = reply
+        .content
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_sub(1))
+        .and_then(|index| results.get(index));
+
+    match picked {
+        Some(result) => {
+            play_or_enqueue(ctx, msg, PlayArgs::YoutubeLink(result.url.clone()), false).await
+        }
+        None => {
+            check_msg(msg.channel_id.say(&ctx.http, "Search cancelled").await);
+            Ok(())
+        }
+    }
+}
+
 pub async fn send_msg(channel_id: ChannelId, msg: &str) -> SerenityResult<Message> {
     let token = std::env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
     let discord_http = Http::new_with_token(&token);
@@ -587,22 +1123,214 @@ pub async fn send_msg(channel_id: ChannelId, msg: &str) -> SerenityResult<Messag
     })
 }
 
+/// Resolves the song's audio through youtube-dl, for when it starts playing.
+/// Sources are restartable so that the resulting tracks can be seeked.
+/// Queries are looked up through the search provider when queued, only songs
+/// saved before that still come in as queries.
 pub async fn input_from_yt_url(
     play_args: &PlayArgs,
-    channel_id: ChannelId,
 ) -> Result<Input, songbird::input::error::Error> {
     match play_args {
-        PlayArgs::SearchQuery(q) => songbird::input::ytdl_search(q).await.map_err(|e| {
-            let _ = send_msg(channel_id, &format!("Error sourcing ffmpeg : {e}"));
-            e
-        }),
-        PlayArgs::YoutubeLink(url) => songbird::ytdl(url).await.map_err(|e| {
-            let _ = send_msg(channel_id, &format!("Error sourcing ffmpeg : {e}"));
-            e
-        }),
+        PlayArgs::SearchQuery(q) => Restartable::ytdl_search(q, false).await.map(Input::from),
+        PlayArgs::YoutubeLink(url) => Restartable::ytdl(url.clone(), false).await.map(Input::from),
+    }
+}
+
+const DEFAULT_SEEK_OFFSET: Duration = Duration::from_secs(10);
+
+/// Reads an optional offset for `ff` and `rewind`, defaulting to ten seconds.
+fn parse_offset(args: &mut Args) -> Option<Duration> {
+    if args.is_empty() {
+        return Some(DEFAULT_SEEK_OFFSET);
+    }
+    args.single::<String>()
+        .ok()
+        .as_deref()
+        .and_then(parse_duration)
+}
+
+enum SeekTarget {
+    To(Duration),
+    Forward(Duration),
+    Back(Duration),
+}
+
+async fn seek_current(ctx: &Context, msg: &Message, target: SeekTarget) -> CommandResult {
+    let guild = msg.guild(&ctx.cache).await.expect("Guild not found.");
+    let backend = backend(ctx).await;
+    let current = match backend.position(guild.id).await {
+        Ok(Some(current)) => current,
+        Ok(None) => {
+            check_msg(msg.channel_id.say(&ctx.http, "Nothing is playing").await);
+            return Ok(());
+        }
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+    let position = match target {
+        SeekTarget::To(position) => Some(position),
+        SeekTarget::Forward(offset) => current.checked_add(offset),
+        SeekTarget::Back(offset) => Some(current.saturating_sub(offset)),
+    };
+    let position = match position {
+        Some(position) => position,
+        None => {
+            check_msg(
+                msg.channel_id
+                    .say(&ctx.http, "That's past the end of the track")
+                    .await,
+            );
+            return Ok(());
+        }
+    };
+
+    let duration = match backend.seek(guild.id, position).await {
+        Ok(duration) => duration,
+        Err(why) => return reply_rejection(ctx, msg, why).await,
+    };
+
+    check_msg(
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "Seeked to {} / {}",
+                    format_duration(position),
+                    duration
+                        .map(format_duration)
+                        .unwrap_or_else(|| "?".to_string())
+                ),
+            )
+            .await,
+    );
+
+    Ok(())
+}
+
+/// Remembers what a song resolved to for the next time it gets queued.
+pub async fn cache_resolved_track(
+    redis_client: &redis::Client,
+    play_arg: &PlayArgs,
+    track: Option<TrackMetadata>,
+) {
+    let track = match track {
+        Some(track) => track,
+        None => return,
+    };
+    let query = match play_arg {
+        PlayArgs::SearchQuery(query) => Some(query.as_str()),
+        PlayArgs::YoutubeLink(_) => None,
+    };
+    let result = match redis_client.get_async_connection().await {
+        Ok(conn) => RedisStore::new(conn).cache_track(query, &track).await,
+        Err(e) => Err(RedisStoreError::from(e)),
+    };
+    if let Err(why) = result {
+        eprintln!("Failed to cache the track : {:?}", why);
+    }
+}
+
+pub fn volume_gain(volume: u16) -> f32 {
+    volume as f32 / 100.0
+}
+
+pub async fn stored_volume(redis_client: &redis::Client, guild_id: GuildId) -> u16 {
+    let conn = match redis_client.get_async_connection().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Failed to get Redis connection : {}", e);
+            return DEFAULT_VOLUME;
+        }
+    };
+    match RedisStore::new(conn).get_volume(guild_id).await {
+        Ok(volume) => volume.unwrap_or(DEFAULT_VOLUME),
+        Err(e) => {
+            eprintln!("Failed to get volume : {:?}", e);
+            DEFAULT_VOLUME
+        }
+    }
+}
+
+/// The backend playing the music, which is set up once on startup.
+async fn backend(ctx: &Context) -> Arc<dyn MusicBackend> {
+    ctx.data
+        .read()
+        .await
+        .get::<MusicBackendContainer>()
+        .cloned()
+        .expect("MusicBackend placed in at initialisation.")
+}
+
+/// Tells the user why the player turned a request down, or passes the error on
+/// when something actually went wrong.
+async fn reply_rejection(
+    ctx: &Context,
+    msg: &Message,
+    why: impl Into<BackendError>,
+) -> CommandResult {
+    match why.into() {
+        BackendError::Rejected(why) => {
+            check_msg(msg.channel_id.say(&ctx.http, why).await);
+            Ok(())
+        }
+        why => Err(why.into()),
+    }
+}
+
+/// Reads a 1-based queue position from the arguments and turns it into an
+/// index. The player checks it against the queue.
+fn parse_queue_position(args: &mut Args) -> Result<usize, String> {
+    match args.single::<usize>() {
+        Ok(position) if position > 0 => Ok(position - 1),
+        _ => Err("Expected a queue position, starting at 1".to_string()),
     }
 }
 
+pub fn loop_mode_suffix(loop_mode: LoopMode) -> String {
+    match loop_mode {
+        LoopMode::Off => String::new(),
+        mode => format!(" (looping {mode})"),
+    }
+}
+
+fn queued_reply(name: &str, next: bool, queued: &Enqueued) -> String {
+    format!(
+        "Queing {}{}, {} tracks in queue{}",
+        name,
+        if next { " to play next" } else { "" },
+        queued.queue_len,
+        queued
+            .starts_in
+            .map(|(wait, unknown_durations)| format!(
+                ", starts in {}{}",
+                format_eta(wait),
+                if unknown_durations { "+" } else { "" }
+            ))
+            .unwrap_or_default()
+    )
+}
+
+fn queue_entry_line(
+    position: usize,
+    song: &QueuedSong,
+    starts_in: Option<(Duration, bool)>,
+) -> String {
+    format!(
+        "`{position}.` {} `{}` {}{}\n",
+        song.name,
+        song.duration
+            .map(format_duration)
+            .unwrap_or_else(|| "?".to_string()),
+        song.requester.mention(),
+        starts_in
+            .map(|(wait, unknown_durations)| format!(
+                ", in {}{}",
+                format_eta(wait),
+                if unknown_durations { "+" } else { "" }
+            ))
+            .unwrap_or_default()
+    )
+}
+
 /// Checks that a message successfully sent; if not, then logs why to stdout.
 fn check_msg(result: SerenityResult<Message>) {
     if let Err(why) = result {
@@ -2,16 +2,22 @@ use serenity::framework::standard::{macros::command, CommandResult};
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 
-use crate::ShardManagerContainer;
+use crate::redis_store::RedisStore;
+use crate::{shutdown, RedisClientContainer, ShardManagerContainer};
 
 #[command]
 #[owners_only]
 async fn quit1(ctx: &Context, msg: &Message) -> CommandResult {
-    let data = ctx.data.read().await;
+    let shard_manager = ctx
+        .data
+        .read()
+        .await
+        .get::<ShardManagerContainer>()
+        .cloned();
 
-    if let Some(manager) = data.get::<ShardManagerContainer>() {
+    if let Some(manager) = shard_manager {
         msg.reply(ctx, "Shutting down!").await?;
-        manager.lock().await.shutdown_all().await;
+        shutdown(ctx.data.clone(), manager).await;
     } else {
         msg.reply(ctx, "There was a problem getting the shard manager")
             .await?;
@@ -21,3 +27,41 @@ async fn quit1(ctx: &Context, msg: &Message) -> CommandResult {
 
     Ok(())
 }
+
+#[command]
+#[owners_only]
+async fn cachestats(ctx: &Context, msg: &Message) -> CommandResult {
+    let redis_client = match ctx.data.read().await.get::<RedisClientContainer>() {
+        Some(redis_client) => redis_client.clone(),
+        None => {
+            msg.reply(ctx, "Redis client not found").await?;
+            return Ok(());
+        }
+    };
+
+    let stats = match redis_client.get_async_connection().await {
+        Ok(conn) => RedisStore::new(conn).track_cache_stats().await,
+        Err(e) => Err(e.into()),
+    };
+    match stats {
+        Ok((hits, misses)) => {
+            let lookups = hits + misses;
+            let hit_rate = if lookups > 0 {
+                hits as f64 / lookups as f64 * 100.0
+            } else {
+                0.0
+            };
+            msg.reply(
+                ctx,
+                format!("Track cache: {hits} hits, {misses} misses ({hit_rate:.1}% hit rate)"),
+            )
+            .await?;
+        }
+        Err(why) => {
+            eprintln!("Failed to get track cache stats : {:?}", why);
+            msg.reply(ctx, "Failed to get track cache stats").await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1363 @@
+use dashmap::DashMap;
+use flume::{Receiver, Sender};
+use serenity::model::prelude::*;
+use serenity::{async_trait, prelude::*};
+use songbird::input::{Input, Metadata};
+use songbird::tracks::{TrackError, TrackHandle, TrackResult};
+use songbird::{Event, EventContext, EventHandler, Songbird, TrackEvent};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use self::state::{Effect, InvalidTransition, PlayerEvent};
+use super::music::{
+    cache_resolved_track, input_from_yt_url, loop_mode_suffix, send_msg, volume_gain, LoopMode,
+    SongQueue,
+};
+use crate::backend::Enqueued;
+use crate::redis_store::{GuildSession, QueuedSong, RedisStore, RedisStoreError, TrackMetadata};
+use crate::util::{format_duration, time_until};
+
+mod state;
+
+pub use self::state::PlayerState;
+
+/// How often the playback position of a session gets written to Redis.
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(15);
+/// How long before the end of a track to start resolving the next one.
+const PRELOAD_AHEAD: Duration = Duration::from_secs(15);
+/// How long a player may sit idle before it's shut down, unless configured.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+pub enum PlayerError {
+    /// The request doesn't make sense right now, with a message saying why.
+    Rejected(String),
+    Redis(RedisStoreError),
+    Track(TrackError),
+    /// The player task is gone.
+    Closed,
+}
+
+impl From<RedisStoreError> for PlayerError {
+    fn from(err: RedisStoreError) -> Self {
+        PlayerError::Redis(err)
+    }
+}
+
+impl From<TrackError> for PlayerError {
+    fn from(err: TrackError) -> Self {
+        PlayerError::Track(err)
+    }
+}
+
+impl std::fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlayerError::Rejected(why) => write!(f, "{}", why),
+            PlayerError::Redis(err) => write!(f, "{}", err),
+            PlayerError::Track(err) => write!(f, "Track error: {}", err),
+            PlayerError::Closed => write!(f, "The player is not running"),
+        }
+    }
+}
+
+impl std::error::Error for PlayerError {}
+
+type Reply<T> = Sender<Result<T, PlayerError>>;
+
+/// Changes to the queue. Positions are 0-based and checked when applied.
+#[derive(Debug, Clone, Copy)]
+pub enum QueueEdit {
+    Remove(usize),
+    Move { from: usize, to: usize },
+    Swap(usize, usize),
+    Clear,
+    Shuffle,
+}
+
+impl QueueEdit {
+    /// Makes the change to `queue`, returning the songs it was about. In fair
+    /// mode the queue keeps taking turns between requesters, with whoever
+    /// requested the current song last.
+    pub fn apply(
+        self,
+        queue: &mut SongQueue,
+        fair_scheduling: bool,
+        current_requester: Option<UserId>,
+    ) -> Result<Vec<QueuedSong>, PlayerError> {
+        let len = queue.len();
+        Ok(match self {
+            QueueEdit::Remove(index) => {
+                check_queue_position(index, len)?;
+                queue.remove(index).into_iter().collect()
+            }
+            QueueEdit::Move { from, to } => {
+                check_queue_position(from, len)?;
+                check_queue_position(to, len)?;
+                queue.move_song(from, to).cloned().into_iter().collect()
+            }
+            QueueEdit::Swap(a, b) => {
+                check_queue_position(a, len)?;
+                check_queue_position(b, len)?;
+                queue.swap(a, b);
+                [a, b]
+                    .iter()
+                    .filter_map(|&index| queue.get(index).cloned())
+                    .collect()
+            }
+            QueueEdit::Clear => {
+                let cleared = queue.iter().cloned().collect();
+                *queue = SongQueue::default();
+                cleared
+            }
+            QueueEdit::Shuffle => {
+                if queue.is_empty() {
+                    return Err(PlayerError::Rejected("The queue is empty".to_string()));
+                }
+                queue.shuffle();
+                // Shuffling only mixes up each requester's own songs while in fair mode.
+                if fair_scheduling {
+                    queue.interleave_by_requester(current_requester);
+                }
+                Vec::new()
+            }
+        })
+    }
+}
+
+/// The songs a queue edit was about, and how many are left in the queue.
+pub struct QueueEdited {
+    pub songs: Vec<QueuedSong>,
+    pub queue_len: usize,
+}
+
+pub enum PlayOutcome {
+    /// The player is getting the song ready, and lets the channel know once it
+    /// plays.
+    Started,
+    /// Something else is playing or about to.
+    Queued(Enqueued),
+}
+
+pub struct PlayerStatus {
+    pub state: PlayerState<TrackHandle>,
+    pub elapsed: Duration,
+    pub volume: u16,
+}
+
+/// Everything needed to show the queue or what is playing.
+pub struct PlayerSnapshot {
+    pub state: PlayerState<TrackHandle>,
+    pub elapsed: Duration,
+    pub metadata: Option<Metadata>,
+    pub queue: SongQueue,
+    pub loop_mode: LoopMode,
+    pub fair_scheduling: bool,
+}
+
+/// What a guild's player can be asked to do. Requests carry a channel the
+/// answer is sent back on, notifications from songbird events don't.
+pub enum PlayerCommand {
+    Status {
+        reply: Reply<PlayerStatus>,
+    },
+    Snapshot {
+        reply: Reply<PlayerSnapshot>,
+    },
+    Play {
+        song: QueuedSong,
+        voice_channel_id: ChannelId,
+        text_channel_id: ChannelId,
+        next: bool,
+        reply: Reply<PlayOutcome>,
+    },
+    Restore {
+        session: GuildSession,
+        reply: Reply<()>,
+    },
+    Skip {
+        count: usize,
+        reply: Reply<usize>,
+    },
+    Pause {
+        reply: Reply<()>,
+    },
+    Unpause {
+        reply: Reply<()>,
+    },
+    Seek {
+        position: Duration,
+        reply: Reply<Option<Duration>>,
+    },
+    EditQueue {
+        edit: QueueEdit,
+        reply: Reply<QueueEdited>,
+    },
+    SetLoopMode {
+        mode: Option<LoopMode>,
+        reply: Reply<LoopMode>,
+    },
+    SetFairScheduling {
+        enabled: Option<bool>,
+        reply: Reply<bool>,
+    },
+    SetVolume {
+        volume: u16,
+        reply: Reply<()>,
+    },
+    SaveSession {
+        reply: Option<Reply<()>>,
+    },
+    Leave {
+        reply: Reply<()>,
+    },
+    Suspend {
+        reply: Reply<()>,
+    },
+    Connected {
+        channel_id: ChannelId,
+        joined: bool,
+    },
+    /// The audio of the song with the given key, resolved for it to start.
+    Buffered {
+        key: String,
+        input: Result<Input, songbird::input::error::Error>,
+    },
+    TrackEnded(TrackHandle),
+    PreloadDue(TrackHandle),
+    Preloaded {
+        key: String,
+        input: Input,
+    },
+}
+
+/// A handle to the task playing music in a guild. The task owns all of the
+/// guild's player state and works through commands one at a time, so
+/// commands and track events can't step on each other.
+#[derive(Debug, Clone)]
+pub struct GuildPlayer {
+    commands: Sender<PlayerCommand>,
+}
+
+impl GuildPlayer {
+    /// Starts the player task of a guild. It removes itself from `music_state`
+    /// once it's been idle for too long, so it must only be spawned into an
+    /// empty entry of `music_state.players`.
+    pub fn spawn(
+        guild_id: GuildId,
+        text_channel_id: ChannelId,
+        volume: u16,
+        manager: Arc<Songbird>,
+        redis_client: redis::Client,
+        music_state: &Arc<MusicState>,
+    ) -> Self {
+        let (commands, receiver) = flume::unbounded();
+        let player = GuildPlayer { commands };
+        let state = GuildMusicState {
+            guild_id,
+            music_state: Arc::downgrade(music_state),
+            idle_timeout: music_state.idle_timeout,
+            text_channel_id,
+            state: PlayerState::Disconnected,
+            start: Start::Requested,
+            loop_mode: LoopMode::default(),
+            fair_scheduling: false,
+            volume,
+            manager,
+            redis_client,
+            suspended: false,
+            preload: Preload::default(),
+            player: player.clone(),
+        };
+        tokio::spawn(state.run(receiver));
+        player
+    }
+
+    /// Sends a command without waiting on it.
+    fn send(&self, command: PlayerCommand) {
+        if self.commands.send(command).is_err() {
+            eprintln!("The player is not running");
+        }
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(Reply<T>) -> PlayerCommand,
+    ) -> Result<T, PlayerError> {
+        let (reply, response) = flume::bounded(1);
+        self.commands
+            .send_async(command(reply))
+            .await
+            .map_err(|_| PlayerError::Closed)?;
+        response
+            .recv_async()
+            .await
+            .map_err(|_| PlayerError::Closed)?
+    }
+
+    pub async fn status(&self) -> Result<PlayerStatus, PlayerError> {
+        self.request(|reply| PlayerCommand::Status { reply }).await
+    }
+
+    pub async fn snapshot(&self) -> Result<PlayerSnapshot, PlayerError> {
+        self.request(|reply| PlayerCommand::Snapshot { reply })
+            .await
+    }
+
+    /// Starts playing the song in the voice channel when idle, otherwise queues
+    /// it at the back of the queue, or at the front when `next` is set.
+    pub async fn play(
+        &self,
+        song: QueuedSong,
+        voice_channel_id: ChannelId,
+        text_channel_id: ChannelId,
+        next: bool,
+    ) -> Result<PlayOutcome, PlayerError> {
+        self.request(|reply| PlayerCommand::Play {
+            song,
+            voice_channel_id,
+            text_channel_id,
+            next,
+            reply,
+        })
+        .await
+    }
+
+    /// Picks up a session saved before a restart. The player rejoins and
+    /// resumes in the background, and lets the text channel know how it went.
+    pub async fn restore(&self, session: GuildSession) -> Result<(), PlayerError> {
+        self.request(|reply| PlayerCommand::Restore { session, reply })
+            .await
+    }
+
+    /// Skips `count` songs, counting the current one. Returns how many songs
+    /// are left in the queue.
+    pub async fn skip(&self, count: usize) -> Result<usize, PlayerError> {
+        self.request(|reply| PlayerCommand::Skip { count, reply })
+            .await
+    }
+
+    pub async fn pause(&self) -> Result<(), PlayerError> {
+        self.request(|reply| PlayerCommand::Pause { reply }).await
+    }
+
+    pub async fn unpause(&self) -> Result<(), PlayerError> {
+        self.request(|reply| PlayerCommand::Unpause { reply }).await
+    }
+
+    /// Returns the length of the track, if known.
+    pub async fn seek(&self, position: Duration) -> Result<Option<Duration>, PlayerError> {
+        self.request(|reply| PlayerCommand::Seek { position, reply })
+            .await
+    }
+
+    pub async fn edit_queue(&self, edit: QueueEdit) -> Result<QueueEdited, PlayerError> {
+        self.request(|reply| PlayerCommand::EditQueue { edit, reply })
+            .await
+    }
+
+    /// Sets the loop mode, or moves on to the next one when `None`.
+    pub async fn set_loop_mode(&self, mode: Option<LoopMode>) -> Result<LoopMode, PlayerError> {
+        self.request(|reply| PlayerCommand::SetLoopMode { mode, reply })
+            .await
+    }
+
+    /// Turns fair mode on or off, or toggles it when `None`.
+    pub async fn set_fair_scheduling(&self, enabled: Option<bool>) -> Result<bool, PlayerError> {
+        self.request(|reply| PlayerCommand::SetFairScheduling { enabled, reply })
+            .await
+    }
+
+    pub async fn set_volume(&self, volume: u16) -> Result<(), PlayerError> {
+        self.request(|reply| PlayerCommand::SetVolume { volume, reply })
+            .await
+    }
+
+    /// Stops playing and leaves voice, keeping the queue.
+    pub async fn leave(&self) -> Result<(), PlayerError> {
+        self.request(|reply| PlayerCommand::Leave { reply }).await
+    }
+
+    /// Saves the session for the next start and leaves voice.
+    pub async fn suspend(&self) -> Result<(), PlayerError> {
+        self.request(|reply| PlayerCommand::Suspend { reply }).await
+    }
+}
+
+#[derive(Debug)]
+pub struct MusicState {
+    pub players: DashMap<GuildId, GuildPlayer>,
+    /// How long a player can go without playing anything or being asked
+    /// anything before it leaves voice and shuts down.
+    pub idle_timeout: Duration,
+}
+
+impl Default for MusicState {
+    fn default() -> Self {
+        MusicState {
+            players: DashMap::new(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl MusicState {
+    /// Reads the idle timeout in seconds from `PLAYER_IDLE_TIMEOUT`, if set.
+    pub fn from_env() -> Self {
+        let idle_timeout = std::env::var("PLAYER_IDLE_TIMEOUT")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+        MusicState {
+            idle_timeout,
+            ..MusicState::default()
+        }
+    }
+}
+
+impl TypeMapKey for MusicState {
+    type Value = Arc<MusicState>;
+}
+
+/// The next song, resolved ahead of time so it can start the moment the
+/// current one ends.
+#[derive(Debug, Default)]
+struct Preload {
+    /// Set once the current track is close enough to its end.
+    due: bool,
+    /// The serialized song being resolved, to tell whether it is still the one
+    /// that plays next.
+    key: String,
+    /// Only ever used through `&mut`, the mutex is there because `Input` isn't
+    /// `Sync` and the state gets borrowed across awaits.
+    input: Mutex<Option<Input>>,
+}
+
+/// Why the track being buffered is about to start, which decides what happens
+/// once it does.
+#[derive(Debug, Clone, Copy)]
+enum Start {
+    /// Someone asked for the song while nothing was playing.
+    Requested,
+    /// The song is next in the queue.
+    Next,
+    /// Picking up a session from before a restart.
+    Resume { position: Duration, paused: bool },
+}
+
+/// A guild's player state, owned by its player task.
+struct GuildMusicState {
+    guild_id: GuildId,
+    /// Where the player is registered, to take itself out once idle.
+    music_state: Weak<MusicState>,
+    idle_timeout: Duration,
+    /// Where announcements about the session go.
+    text_channel_id: ChannelId,
+    state: PlayerState<TrackHandle>,
+    start: Start,
+    loop_mode: LoopMode,
+    /// Interleave the queue by requester so one person can't hog it.
+    fair_scheduling: bool,
+    /// Volume in percent, applied to every track as it starts.
+    volume: u16,
+    manager: Arc<Songbird>,
+    /// The queue is kept in Redis so it survives restarts.
+    redis_client: redis::Client,
+    /// Set once the session has been saved for a shutdown, so that leaving
+    /// the voice channel afterwards doesn't touch it.
+    suspended: bool,
+    preload: Preload,
+    /// For track events and background work to report back.
+    player: GuildPlayer,
+}
+
+impl GuildMusicState {
+    async fn run(mut self, commands: Receiver<PlayerCommand>) {
+        self.load_settings().await;
+        loop {
+            // Anything in progress keeps the player around, paused songs too.
+            let command = if self.state.is_busy() {
+                commands.recv_async().await
+            } else {
+                match tokio::time::timeout(self.idle_timeout, commands.recv_async()).await {
+                    Ok(command) => command,
+                    Err(_) if self.evict(&commands).await => break,
+                    Err(_) => continue,
+                }
+            };
+            match command {
+                Ok(command) => self.handle_command(command).await,
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Saves the settings, leaves voice and takes the player out of the music
+    /// state, unless a command came in meanwhile. Returns whether it did.
+    /// The queue stays in Redis for the next player to pick up.
+    async fn evict(&mut self, commands: &Receiver<PlayerCommand>) -> bool {
+        let music_state = match self.music_state.upgrade() {
+            Some(music_state) => music_state,
+            None => return true,
+        };
+        if let Err(why) = self.save_settings().await {
+            eprintln!("Failed to save the player settings : {:?}", why);
+        }
+        // Only this player ever takes its entry out, so it's still its own.
+        // Checking for commands under the entry's lock means nobody can pick
+        // the player up afterwards. Those who got it earlier may still be
+        // about to send theirs, they get `PlayerError::Closed` and have to
+        // spawn a new player.
+        let evicted = music_state
+            .players
+            .remove_if(&self.guild_id, |_, _| commands.is_empty())
+            .is_some();
+        if evicted {
+            let _ = self.apply(PlayerEvent::Leave).await;
+        }
+        evicted
+    }
+
+    /// Picks up the settings saved when the guild's last player shut down.
+    async fn load_settings(&mut self) {
+        let settings = async {
+            let mut redis_store = self.redis_store().await?;
+            let loop_mode = redis_store.get_loop_mode(self.guild_id).await?;
+            let fair_scheduling = redis_store.get_fair_scheduling(self.guild_id).await?;
+            Ok::<_, RedisStoreError>((loop_mode, fair_scheduling))
+        };
+        match settings.await {
+            Ok((loop_mode, fair_scheduling)) => {
+                if let Some(loop_mode) = loop_mode.and_then(|mode| mode.parse().ok()) {
+                    self.loop_mode = loop_mode;
+                }
+                self.fair_scheduling = fair_scheduling.unwrap_or_default();
+            }
+            Err(why) => eprintln!("Failed to load the player settings : {:?}", why),
+        }
+    }
+
+    async fn save_settings(&self) -> Result<(), RedisStoreError> {
+        let mut redis_store = self.redis_store().await?;
+        redis_store
+            .set_loop_mode(self.guild_id, &self.loop_mode.to_string())
+            .await?;
+        redis_store
+            .set_fair_scheduling(self.guild_id, self.fair_scheduling)
+            .await
+    }
+
+    async fn handle_command(&mut self, command: PlayerCommand) {
+        // Whoever asked may have given up waiting, that's fine.
+        match command {
+            PlayerCommand::Status { reply } => {
+                let _ = reply.send(Ok(PlayerStatus {
+                    state: self.state.clone(),
+                    elapsed: self.elapsed().await,
+                    volume: self.volume,
+                }));
+            }
+            PlayerCommand::Snapshot { reply } => {
+                let _ = reply.send(self.snapshot().await);
+            }
+            PlayerCommand::Play {
+                song,
+                voice_channel_id,
+                text_channel_id,
+                next,
+                reply,
+            } => {
+                let result = self
+                    .play(song, voice_channel_id, text_channel_id, next)
+                    .await;
+                let _ = reply.send(result);
+            }
+            PlayerCommand::Restore { session, reply } => {
+                let _ = reply.send(self.restore(session).await);
+            }
+            PlayerCommand::Skip { count, reply } => {
+                let _ = reply.send(self.skip(count).await);
+            }
+            PlayerCommand::Pause { reply } => {
+                let result = self
+                    .apply(PlayerEvent::Pause)
+                    .await
+                    .map_err(|_| PlayerError::Rejected("Not playing so can't pause".to_string()));
+                let _ = reply.send(result);
+            }
+            PlayerCommand::Unpause { reply } => {
+                let result = self
+                    .apply(PlayerEvent::Resume)
+                    .await
+                    .map_err(|_| PlayerError::Rejected("Not paused so can't unpause".to_string()));
+                let _ = reply.send(result);
+            }
+            PlayerCommand::Seek { position, reply } => {
+                let _ = reply.send(self.seek(position).await);
+            }
+            PlayerCommand::EditQueue { edit, reply } => {
+                let _ = reply.send(self.edit_queue(edit).await);
+            }
+            PlayerCommand::SetLoopMode { mode, reply } => {
+                self.loop_mode = mode.unwrap_or_else(|| self.loop_mode.next());
+                self.refresh_preload().await;
+                let _ = reply.send(Ok(self.loop_mode));
+            }
+            PlayerCommand::SetFairScheduling { enabled, reply } => {
+                let _ = reply.send(self.set_fair_scheduling(enabled).await);
+            }
+            PlayerCommand::SetVolume { volume, reply } => {
+                self.volume = volume;
+                let result = match self.state.track() {
+                    Some(track) => track
+                        .set_volume(volume_gain(volume))
+                        .map_err(PlayerError::from),
+                    None => Ok(()),
+                };
+                let _ = reply.send(result);
+            }
+            PlayerCommand::SaveSession { reply } => {
+                let result = self.save_session().await.map_err(PlayerError::from);
+                match reply {
+                    Some(reply) => {
+                        let _ = reply.send(result);
+                    }
+                    None => {
+                        if let Err(why) = result {
+                            eprintln!("Failed to save the session : {:?}", why);
+                        }
+                    }
+                }
+            }
+            PlayerCommand::Leave { reply } => {
+                // Leaving works from any state.
+                let _ = self.apply(PlayerEvent::Leave).await;
+                let _ = reply.send(Ok(()));
+            }
+            PlayerCommand::Suspend { reply } => {
+                self.suspend().await;
+                let _ = reply.send(Ok(()));
+            }
+            PlayerCommand::Connected { channel_id, joined } => {
+                self.connected(channel_id, joined).await
+            }
+            PlayerCommand::Buffered { key, input } => self.buffered(key, input).await,
+            PlayerCommand::TrackEnded(ended) => self.track_ended(ended).await,
+            PlayerCommand::PreloadDue(track) => {
+                if self.is_current(&track) {
+                    self.preload.due = true;
+                    self.refresh_preload().await;
+                }
+            }
+            PlayerCommand::Preloaded { key, input } => {
+                // The track may have ended while resolving, then this is too late.
+                if self.preload.due && self.preload.key == key {
+                    *self.preload.input.get_mut() = Some(input);
+                }
+            }
+        }
+    }
+
+    /// Moves the player along with `event` and carries out what that takes.
+    /// Slow work like joining voice or running youtube-dl happens in the
+    /// background and reports back with another command.
+    async fn apply(&mut self, event: PlayerEvent<TrackHandle>) -> Result<(), InvalidTransition> {
+        for effect in self.state.apply(event)? {
+            match effect {
+                Effect::JoinVoice(channel_id) => {
+                    let manager = self.manager.clone();
+                    let guild_id = self.guild_id;
+                    let player = self.player.clone();
+                    tokio::spawn(async move {
+                        let (_, joined) = manager.join(guild_id, channel_id).await;
+                        if let Err(why) = &joined {
+                            eprintln!("Failed to join {} : {:?}", channel_id, why);
+                        }
+                        player.send(PlayerCommand::Connected {
+                            channel_id,
+                            joined: joined.is_ok(),
+                        });
+                    });
+                }
+                Effect::ResolveAudio(song) => self.resolve_audio(song),
+                Effect::StopTrack(track) => {
+                    // Already over unless it was paused.
+                    let _ = track.stop();
+                }
+                Effect::PauseTrack(track) => {
+                    if let Err(why) = track.pause() {
+                        eprintln!("Failed to pause the track : {:?}", why);
+                    }
+                }
+                Effect::ResumeTrack(track) => {
+                    if let Err(why) = track.play() {
+                        eprintln!("Failed to resume the track : {:?}", why);
+                    }
+                }
+                Effect::LeaveVoice => {
+                    // Not being in voice in the first place is fine.
+                    let _ = self.manager.leave(self.guild_id).await;
+                }
+                Effect::SaveSession => {
+                    if let Err(why) = self.save_session().await {
+                        eprintln!("Failed to save the session : {:?}", why);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_current(&self, track: &TrackHandle) -> bool {
+        self.state.track().map(TrackHandle::uuid) == Some(track.uuid())
+    }
+
+    fn current_requester(&self) -> Option<UserId> {
+        self.state.song().map(|song| song.requester)
+    }
+
+    async fn elapsed(&self) -> Duration {
+        match self.state.track() {
+            Some(track) => track
+                .get_info()
+                .await
+                .map(|info| info.position)
+                .unwrap_or_default(),
+            None => Duration::ZERO,
+        }
+    }
+
+    async fn voice_channel(&self) -> Option<ChannelId> {
+        let handler_lock = self.manager.get(self.guild_id)?;
+        let channel_id = handler_lock.lock().await.current_channel()?;
+        Some(ChannelId(channel_id.0))
+    }
+
+    async fn redis_store(&self) -> Result<RedisStore, RedisStoreError> {
+        Ok(RedisStore::new(
+            self.redis_client.get_async_connection().await?,
+        ))
+    }
+
+    async fn load_queue(&self) -> Result<SongQueue, RedisStoreError> {
+        let songs = self
+            .redis_store()
+            .await?
+            .get_queue(self.guild_id)
+            .await?
+            .unwrap_or_default();
+        Ok(SongQueue::from(songs))
+    }
+
+    async fn save_queue(&self, queue: &SongQueue) -> Result<(), RedisStoreError> {
+        self.redis_store()
+            .await?
+            .set_queue(self.guild_id, queue.iter())
+            .await
+    }
+
+    async fn queue_len(&self) -> Result<usize, RedisStoreError> {
+        self.redis_store().await?.queue_len(self.guild_id).await
+    }
+
+    /// Stores what is playing and where so it can be resumed after a restart,
+    /// or forgets about it once nothing is.
+    async fn save_session(&self) -> Result<(), RedisStoreError> {
+        if self.suspended {
+            return Ok(());
+        }
+        let voice_channel_id = match &self.state {
+            PlayerState::Connecting { channel_id, .. } => Some(*channel_id),
+            _ => self.voice_channel().await,
+        };
+        let mut redis_store = self.redis_store().await?;
+        match (self.state.song(), voice_channel_id) {
+            (Some(song), Some(voice_channel_id)) => {
+                let session = GuildSession {
+                    voice_channel_id,
+                    text_channel_id: self.text_channel_id,
+                    position: self.elapsed().await,
+                    paused: matches!(self.state, PlayerState::Paused { .. }),
+                    song: song.clone(),
+                };
+                redis_store.set_session(self.guild_id, &session).await
+            }
+            _ => redis_store.remove_session(self.guild_id).await,
+        }
+    }
+
+    /// Adds a song to the back of the queue, re-interleaving it in fair mode.
+    /// Returns the position the song ended up at.
+    async fn enqueue(&self, song: QueuedSong) -> Result<usize, RedisStoreError> {
+        if !self.fair_scheduling {
+            let len = self
+                .redis_store()
+                .await?
+                .push_queue(self.guild_id, song)
+                .await?;
+            return Ok(len - 1);
+        }
+        let requester = song.requester;
+        let mut queue = self.load_queue().await?;
+        queue.push_back(song);
+        queue.interleave_by_requester(self.current_requester());
+        self.save_queue(&queue).await?;
+        // Interleaving keeps each requester's songs in order, so the new one is
+        // still the last of theirs.
+        Ok(queue
+            .iter()
+            .rposition(|song| song.requester == requester)
+            .unwrap_or_default())
+    }
+
+    async fn add_to_queue(
+        &mut self,
+        song: QueuedSong,
+        next: bool,
+    ) -> Result<Enqueued, PlayerError> {
+        let position = if next {
+            self.redis_store()
+                .await?
+                .push_queue_front(self.guild_id, song)
+                .await?;
+            0
+        } else {
+            self.enqueue(song).await?
+        };
+        self.refresh_preload().await;
+        let queue = self.load_queue().await?;
+        // Looping a single track means the queue never moves on.
+        let starts_in = if self.loop_mode == LoopMode::Track {
+            None
+        } else {
+            let elapsed = self.elapsed().await;
+            Some(time_until(
+                self.state.song(),
+                elapsed,
+                queue.iter(),
+                position,
+            ))
+        };
+        Ok(Enqueued {
+            position,
+            queue_len: queue.len(),
+            starts_in,
+        })
+    }
+
+    /// Guesses the song that plays after the current one without changing the
+    /// queue. Fair mode can reorder things once the current song finishes.
+    async fn peek_next_song(&self) -> Result<Option<QueuedSong>, RedisStoreError> {
+        let current = self.state.song().cloned();
+        let head = self.redis_store().await?.peek_queue(self.guild_id).await?;
+        Ok(match self.loop_mode {
+            LoopMode::Track => current.or(head),
+            LoopMode::Queue => head.or(current),
+            LoopMode::Off => head,
+        })
+    }
+
+    /// Picks the song to play after `finished`, following the loop mode.
+    async fn next_song(
+        &self,
+        finished: Option<QueuedSong>,
+    ) -> Result<Option<QueuedSong>, RedisStoreError> {
+        let mut redis_store = self.redis_store().await?;
+        match (self.loop_mode, finished) {
+            (LoopMode::Track, Some(song)) => Ok(Some(song)),
+            (LoopMode::Queue, Some(song)) if self.fair_scheduling => {
+                let requester = song.requester;
+                let mut queue = self.load_queue().await?;
+                queue.push_back(song);
+                queue.interleave_by_requester(Some(requester));
+                let next = queue.pop_front();
+                self.save_queue(&queue).await?;
+                Ok(next)
+            }
+            (LoopMode::Queue, Some(song)) => {
+                redis_store.push_queue(self.guild_id, song).await?;
+                redis_store.pop_queue(self.guild_id).await
+            }
+            _ => redis_store.pop_queue(self.guild_id).await,
+        }
+    }
+
+    async fn snapshot(&self) -> Result<PlayerSnapshot, PlayerError> {
+        Ok(PlayerSnapshot {
+            state: self.state.clone(),
+            elapsed: self.elapsed().await,
+            metadata: self.state.track().map(|track| track.metadata().clone()),
+            queue: self.load_queue().await?,
+            loop_mode: self.loop_mode,
+            fair_scheduling: self.fair_scheduling,
+        })
+    }
+
+    async fn play(
+        &mut self,
+        song: QueuedSong,
+        voice_channel_id: ChannelId,
+        text_channel_id: ChannelId,
+        next: bool,
+    ) -> Result<PlayOutcome, PlayerError> {
+        if self.state.is_busy() {
+            return Ok(PlayOutcome::Queued(self.add_to_queue(song, next).await?));
+        }
+        let in_channel = matches!(self.state, PlayerState::Idle)
+            && self.voice_channel().await == Some(voice_channel_id);
+        let event = if in_channel {
+            PlayerEvent::Load(song)
+        } else {
+            PlayerEvent::Join {
+                song,
+                channel_id: voice_channel_id,
+            }
+        };
+        self.text_channel_id = text_channel_id;
+        self.start = Start::Requested;
+        self.apply(event)
+            .await
+            .map_err(|why| PlayerError::Rejected(why.to_string()))?;
+        Ok(PlayOutcome::Started)
+    }
+
+    async fn restore(&mut self, session: GuildSession) -> Result<(), PlayerError> {
+        self.text_channel_id = session.text_channel_id;
+        self.start = Start::Resume {
+            position: session.position,
+            paused: session.paused,
+        };
+        self.apply(PlayerEvent::Join {
+            song: session.song,
+            channel_id: session.voice_channel_id,
+        })
+        .await
+        .map_err(|why| PlayerError::Rejected(why.to_string()))
+    }
+
+    async fn connected(&mut self, channel_id: ChannelId, joined: bool) {
+        // A leave may have come in while joining, then this is old news.
+        match &self.state {
+            PlayerState::Connecting {
+                channel_id: connecting,
+                ..
+            } if *connecting == channel_id => {}
+            _ => return,
+        }
+        if joined {
+            let _ = self.apply(PlayerEvent::Joined).await;
+            return;
+        }
+        let _ = self.apply(PlayerEvent::Failed).await;
+        let message = match self.start {
+            Start::Resume { .. } => "I was restarted and couldn't pick up where I left off",
+            _ => "Couldn't join the voice channel",
+        };
+        announce(self.text_channel_id, message.to_string());
+    }
+
+    /// Gets the song's audio, from the preload if it's the song that was
+    /// preloaded.
+    fn resolve_audio(&mut self, song: QueuedSong) {
+        let key = song.ser();
+        let preload = std::mem::take(&mut self.preload);
+        if preload.key == key {
+            if let Some(input) = preload.input.into_inner() {
+                self.player.send(PlayerCommand::Buffered {
+                    key,
+                    input: Ok(input),
+                });
+                return;
+            }
+        }
+
+        let player = self.player.clone();
+        tokio::spawn(async move {
+            let input = input_from_yt_url(&song.play).await;
+            player.send(PlayerCommand::Buffered { key, input });
+        });
+    }
+
+    async fn buffered(&mut self, key: String, input: Result<Input, songbird::input::error::Error>) {
+        // The song may have been skipped or the player left while resolving.
+        let song = match &self.state {
+            PlayerState::Buffering { song } if song.ser() == key => song.clone(),
+            _ => return,
+        };
+        let start = std::mem::replace(&mut self.start, Start::Next);
+        let result = match input {
+            Ok(input) => self.start_track(song.clone(), input, start).await,
+            Err(why) => Err(PlayerError::Rejected(format!("{:?}", why))),
+        };
+        let why = match result {
+            Ok(()) => return,
+            Err(why) => why,
+        };
+
+        eprintln!("Failed to play {} : {:?}", song.name, why);
+        let _ = self.apply(PlayerEvent::Failed).await;
+        match start {
+            Start::Requested => announce(song.channel_id, format!("Error: {}", why)),
+            Start::Next => self.advance(None).await,
+            Start::Resume { .. } => announce(
+                self.text_channel_id,
+                "I was restarted and couldn't pick up where I left off".to_string(),
+            ),
+        }
+    }
+
+    /// Plays the resolved song and lets the channel know.
+    async fn start_track(
+        &mut self,
+        song: QueuedSong,
+        input: Input,
+        start: Start,
+    ) -> Result<(), PlayerError> {
+        let handler_lock = self.manager.get(self.guild_id).ok_or_else(|| {
+            PlayerError::Rejected("Not in a voice channel to play in".to_string())
+        })?;
+        let title = input.metadata.title.clone();
+        let url = input.metadata.source_url.clone();
+        let track = TrackMetadata::from_input(&input);
+        cache_resolved_track(&self.redis_client, &song.play, track).await;
+        // Songs played straight away only know what was asked for until now.
+        let song = QueuedSong {
+            name: title.clone().unwrap_or(song.name),
+            duration: input.metadata.duration.or(song.duration),
+            ..song
+        };
+
+        let (position, paused) = match start {
+            Start::Resume { position, paused } => (position, paused),
+            _ => (Duration::ZERO, false),
+        };
+        let remaining = song
+            .duration
+            .map(|duration| duration.saturating_sub(position));
+        let handle = handler_lock.lock().await.play_source(input);
+        let prepared = handle
+            .set_volume(volume_gain(self.volume))
+            .and_then(|_| add_track_events(&handle, &self.player, remaining))
+            .and_then(|_| {
+                if handle.is_seekable() && position > Duration::ZERO {
+                    handle.seek_time(position)
+                } else {
+                    Ok(())
+                }
+            });
+        if let Err(why) = prepared {
+            let _ = handle.stop();
+            return Err(why.into());
+        }
+
+        let announcement = match start {
+            Start::Requested => format!(
+                "Playing {} (<{}>)",
+                title.as_deref().unwrap_or("-"),
+                url.as_deref().unwrap_or("-")
+            ),
+            Start::Next => format!(
+                "Now playing {}(<{}>), {} tracks in queue{}",
+                title.as_deref().unwrap_or("-"),
+                url.as_deref().unwrap_or("-"),
+                self.queue_len().await.unwrap_or_default(),
+                loop_mode_suffix(self.loop_mode)
+            ),
+            Start::Resume { .. } => format!(
+                "Back after a restart, {} {} at {}",
+                if paused { "paused" } else { "resuming" },
+                song.name,
+                format_duration(position)
+            ),
+        };
+        let channel_id = match start {
+            Start::Resume { .. } => self.text_channel_id,
+            _ => song.channel_id,
+        };
+
+        let _ = self
+            .apply(PlayerEvent::Started {
+                song,
+                track: handle,
+            })
+            .await;
+        if paused {
+            let _ = self.apply(PlayerEvent::Pause).await;
+        }
+        announce(channel_id, announcement);
+        Ok(())
+    }
+
+    /// Moves on to the song after `finished`, if there is one.
+    async fn advance(&mut self, finished: Option<QueuedSong>) {
+        let next = match self.next_song(finished).await {
+            Ok(next) => next,
+            Err(why) => {
+                eprintln!("Failed to get the next song : {:?}", why);
+                None
+            }
+        };
+        if let Some(next) = next {
+            self.start = Start::Next;
+            if let Err(why) = self.apply(PlayerEvent::Load(next)).await {
+                eprintln!("Failed to play the next song : {}", why);
+            }
+        }
+    }
+
+    async fn skip(&mut self, count: usize) -> Result<usize, PlayerError> {
+        let current = self.state.song().cloned();
+        // Dropping the current song first means the end event of its track is
+        // ignored instead of replaying it when looping a single track.
+        self.apply(PlayerEvent::Stop)
+            .await
+            .map_err(|_| PlayerError::Rejected("Nothing is playing".to_string()))?;
+
+        let requeue = self.loop_mode == LoopMode::Queue;
+        let mut queue = self.load_queue().await?;
+        if let (Some(song), true) = (current, requeue) {
+            queue.push_back(song);
+        }
+        for _ in 1..count {
+            if let Some(song) = queue.pop_front() {
+                if requeue {
+                    queue.push_back(song);
+                }
+            }
+        }
+        self.save_queue(&queue).await?;
+
+        self.advance(None).await;
+        Ok(queue.len())
+    }
+
+    async fn seek(&mut self, new_position: Duration) -> Result<Option<Duration>, PlayerError> {
+        let (duration, handle) = match (self.state.song(), self.state.track()) {
+            (Some(song), Some(track)) => (song.duration, track.clone()),
+            _ => return Err(PlayerError::Rejected("Nothing is playing".to_string())),
+        };
+        if !handle.is_seekable() {
+            return Err(PlayerError::Rejected(
+                "The current track can't be seeked".to_string(),
+            ));
+        }
+
+        if let Some(duration) = duration {
+            if new_position >= duration {
+                return Err(PlayerError::Rejected(format!(
+                    "{} is past the end of the track ({})",
+                    format_duration(new_position),
+                    format_duration(duration)
+                )));
+            }
+        }
+
+        handle.seek_time(new_position)?;
+        // Seeking moves the track but not its preload event.
+        if let Some(duration) = duration {
+            if duration.saturating_sub(new_position) <= PRELOAD_AHEAD {
+                self.preload.due = true;
+                self.refresh_preload().await;
+            }
+        }
+        Ok(duration)
+    }
+
+    async fn edit_queue(&mut self, edit: QueueEdit) -> Result<QueueEdited, PlayerError> {
+        let mut queue = self.load_queue().await?;
+        let songs = edit.apply(&mut queue, self.fair_scheduling, self.current_requester())?;
+        self.save_queue(&queue).await?;
+        self.refresh_preload().await;
+        Ok(QueueEdited {
+            songs,
+            queue_len: queue.len(),
+        })
+    }
+
+    async fn set_fair_scheduling(&mut self, enabled: Option<bool>) -> Result<bool, PlayerError> {
+        self.fair_scheduling = enabled.unwrap_or(!self.fair_scheduling);
+        if self.fair_scheduling {
+            let mut queue = self.load_queue().await?;
+            queue.interleave_by_requester(self.current_requester());
+            self.save_queue(&queue).await?;
+            self.refresh_preload().await;
+        }
+        Ok(self.fair_scheduling)
+    }
+
+    async fn suspend(&mut self) {
+        if self.suspended {
+            return;
+        }
+        if let Err(why) = self.save_session().await {
+            eprintln!("Failed to save the session : {:?}", why);
+        }
+        self.suspended = true;
+
+        if self.state.is_busy() {
+            let _ = send_msg(
+                self.text_channel_id,
+                "Restarting, back soon to pick up where we left off",
+            )
+            .await;
+        }
+        let _ = self.apply(PlayerEvent::Leave).await;
+    }
+
+    async fn track_ended(&mut self, ended: TrackHandle) {
+        // Replacing or stopping a track ends it too, only the current one counts.
+        if self.suspended || !self.is_current(&ended) {
+            return;
+        }
+        let finished = self.state.song().cloned();
+        if self.apply(PlayerEvent::Finished).await.is_ok() {
+            self.advance(finished).await;
+        }
+    }
+
+    /// Resolves the next song in the background once it's due, or again if the
+    /// queue changed since. Songs are checked again before they are played, so a
+    /// stale preload is never played.
+    async fn refresh_preload(&mut self) {
+        if !self.preload.due || self.suspended {
+            return;
+        }
+        let next = match self.peek_next_song().await {
+            Ok(Some(next)) => next,
+            Ok(None) => {
+                self.preload.key.clear();
+                *self.preload.input.get_mut() = None;
+                return;
+            }
+            Err(why) => {
+                eprintln!("Failed to get the next song : {:?}", why);
+                return;
+            }
+        };
+
+        let key = next.ser();
+        if self.preload.key == key {
+            return;
+        }
+        self.preload.key = key.clone();
+        *self.preload.input.get_mut() = None;
+
+        let player = self.player.clone();
+        tokio::spawn(async move {
+            match input_from_yt_url(&next.play).await {
+                Ok(input) => player.send(PlayerCommand::Preloaded { key, input }),
+                Err(why) => eprintln!("Failed to preload the next song : {:?}", why),
+            }
+        });
+    }
+}
+
+/// Reports an index that is out of range for a queue holding `len` songs.
+fn check_queue_position(index: usize, len: usize) -> Result<(), PlayerError> {
+    if len == 0 {
+        return Err(PlayerError::Rejected("The queue is empty".to_string()));
+    }
+    if index >= len {
+        return Err(PlayerError::Rejected(format!(
+            "There is no track at position {}, the queue has {len} tracks",
+            index + 1
+        )));
+    }
+    Ok(())
+}
+
+/// Sends a message in the background, so the player doesn't wait on Discord.
+fn announce(channel_id: ChannelId, message: String) {
+    tokio::spawn(async move {
+        let _ = send_msg(channel_id, &message).await;
+    });
+}
+
+/// Hooks up the events every track played for a guild needs.
+fn add_track_events(
+    handle: &TrackHandle,
+    player: &GuildPlayer,
+    remaining: Option<Duration>,
+) -> TrackResult<()> {
+    handle.add_event(
+        Event::Track(TrackEvent::End),
+        SongFinishedEventHandler(player.clone()),
+    )?;
+    if let Some(remaining) = remaining {
+        handle.add_event(
+            Event::Delayed(remaining.saturating_sub(PRELOAD_AHEAD)),
+            PreloadEventHandler(player.clone()),
+        )?;
+    }
+    handle.add_event(
+        Event::Periodic(SESSION_SAVE_INTERVAL, None),
+        SessionSaveEventHandler(player.clone()),
+    )
+}
+
+/// The tracks an event fired for.
+fn event_tracks(ctx: &EventContext<'_>) -> Vec<TrackHandle> {
+    match ctx {
+        EventContext::Track(tracks) => tracks.iter().map(|(_, handle)| (*handle).clone()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+pub struct SongFinishedEventHandler(pub GuildPlayer);
+
+#[async_trait]
+impl EventHandler for SongFinishedEventHandler {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<songbird::Event> {
+        for track in event_tracks(ctx) {
+            self.0.send(PlayerCommand::TrackEnded(track));
+        }
+
+        None
+    }
+}
+
+/// Keeps the position in the stored session roughly up to date, in case the
+/// bot goes down without a chance to save it.
+pub struct SessionSaveEventHandler(pub GuildPlayer);
+
+#[async_trait]
+impl EventHandler for SessionSaveEventHandler {
+    async fn act(&self, _: &EventContext<'_>) -> Option<songbird::Event> {
+        self.0.send(PlayerCommand::SaveSession { reply: None });
+
+        None
+    }
+}
+
+/// Starts resolving the next song shortly before the current one ends.
+pub struct PreloadEventHandler(pub GuildPlayer);
+
+#[async_trait]
+impl EventHandler for PreloadEventHandler {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<songbird::Event> {
+        for track in event_tracks(ctx) {
+            self.0.send(PlayerCommand::PreloadDue(track));
+        }
+
+        None
+    }
+}
+
+pub struct SongStartEventHandler(pub GuildPlayer);
+
+#[async_trait]
+impl EventHandler for SongStartEventHandler {
+    async fn act(&self, _: &EventContext<'_>) -> Option<songbird::Event> {
+        None
+    }
+}
+
+pub struct SongPauseEventHandler(pub GuildPlayer);
+
+#[async_trait]
+impl EventHandler for SongPauseEventHandler {
+    async fn act(&self, _: &EventContext<'_>) -> Option<songbird::Event> {
+        None
+    }
+}
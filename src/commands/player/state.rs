@@ -0,0 +1,544 @@
+use serenity::model::prelude::*;
+
+use crate::redis_store::QueuedSong;
+
+/// Where a guild's player is at. `T` is the handle of the playing track, kept
+/// in the states that have one so it can't outlive them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerState<T> {
+    /// In voice with nothing to play.
+    Idle,
+    /// Joining a voice channel to play `song` there.
+    Connecting {
+        song: QueuedSong,
+        channel_id: ChannelId,
+    },
+    /// Resolving the audio of `song` before it starts.
+    Buffering {
+        song: QueuedSong,
+    },
+    Playing {
+        song: QueuedSong,
+        track: T,
+    },
+    Paused {
+        song: QueuedSong,
+        track: T,
+    },
+    /// Not in voice. The queue is kept for when someone plays again.
+    Disconnected,
+}
+
+/// Something that happened to a player, or that someone asked of it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerEvent<T> {
+    /// Go play `song` in a voice channel, moving there when needed.
+    Join {
+        song: QueuedSong,
+        channel_id: ChannelId,
+    },
+    Joined,
+    /// Play `song` in the current voice channel.
+    Load(QueuedSong),
+    /// The audio is ready and the track started. The song may have been filled
+    /// in from what the audio turned out to be.
+    Started {
+        song: QueuedSong,
+        track: T,
+    },
+    Pause,
+    Resume,
+    /// The track played to its end.
+    Finished,
+    /// Joining or resolving the audio didn't work out.
+    Failed,
+    /// Drop whatever is playing or about to, to move on to the next song.
+    Stop,
+    Leave,
+}
+
+/// What has to be done to go along with a transition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect<T> {
+    JoinVoice(ChannelId),
+    /// Resolve the song's audio, reporting back with `Started` or `Failed`.
+    ResolveAudio(QueuedSong),
+    StopTrack(T),
+    PauseTrack(T),
+    ResumeTrack(T),
+    LeaveVoice,
+    /// Store the session, or drop it once there is nothing to resume.
+    SaveSession,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub state: &'static str,
+    pub event: &'static str,
+}
+
+impl std::fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Can't handle {} while {}", self.event, self.state)
+    }
+}
+
+impl std::error::Error for InvalidTransition {}
+
+impl<T: Clone> PlayerState<T> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlayerState::Idle => "idle",
+            PlayerState::Connecting { .. } => "connecting",
+            PlayerState::Buffering { .. } => "buffering",
+            PlayerState::Playing { .. } => "playing",
+            PlayerState::Paused { .. } => "paused",
+            PlayerState::Disconnected => "disconnected",
+        }
+    }
+
+    /// The song playing or about to.
+    pub fn song(&self) -> Option<&QueuedSong> {
+        match self {
+            PlayerState::Connecting { song, .. }
+            | PlayerState::Buffering { song }
+            | PlayerState::Playing { song, .. }
+            | PlayerState::Paused { song, .. } => Some(song),
+            PlayerState::Idle | PlayerState::Disconnected => None,
+        }
+    }
+
+    pub fn track(&self) -> Option<&T> {
+        match self {
+            PlayerState::Playing { track, .. } | PlayerState::Paused { track, .. } => Some(track),
+            _ => None,
+        }
+    }
+
+    /// Whether a new song has to wait its turn in the queue.
+    pub fn is_busy(&self) -> bool {
+        self.song().is_some()
+    }
+
+    /// Moves on to the state `event` leads to and returns what has to be done
+    /// for it, in order. Leaves the state as it was if `event` makes no sense
+    /// in it.
+    pub fn apply(&mut self, event: PlayerEvent<T>) -> Result<Vec<Effect<T>>, InvalidTransition> {
+        let state = std::mem::replace(self, PlayerState::Disconnected);
+        let (next, effects) = match (state, event) {
+            (
+                PlayerState::Idle | PlayerState::Disconnected,
+                PlayerEvent::Join { song, channel_id },
+            ) => (
+                PlayerState::Connecting { song, channel_id },
+                vec![Effect::JoinVoice(channel_id)],
+            ),
+            (PlayerState::Idle, PlayerEvent::Load(song)) => (
+                PlayerState::Buffering { song: song.clone() },
+                vec![Effect::ResolveAudio(song)],
+            ),
+            (PlayerState::Connecting { song, .. }, PlayerEvent::Joined) => (
+                PlayerState::Buffering { song: song.clone() },
+                vec![Effect::ResolveAudio(song)],
+            ),
+            (PlayerState::Connecting { .. }, PlayerEvent::Failed) => {
+                (PlayerState::Disconnected, vec![Effect::SaveSession])
+            }
+            (PlayerState::Buffering { .. }, PlayerEvent::Started { song, track }) => (
+                PlayerState::Playing { song, track },
+                vec![Effect::SaveSession],
+            ),
+            (PlayerState::Buffering { .. }, PlayerEvent::Failed | PlayerEvent::Stop) => {
+                (PlayerState::Idle, vec![Effect::SaveSession])
+            }
+            (PlayerState::Playing { song, track }, PlayerEvent::Pause) => (
+                PlayerState::Paused {
+                    song,
+                    track: track.clone(),
+                },
+                vec![Effect::PauseTrack(track), Effect::SaveSession],
+            ),
+            (PlayerState::Paused { song, track }, PlayerEvent::Resume) => (
+                PlayerState::Playing {
+                    song,
+                    track: track.clone(),
+                },
+                vec![Effect::ResumeTrack(track), Effect::SaveSession],
+            ),
+            (PlayerState::Playing { .. } | PlayerState::Paused { .. }, PlayerEvent::Finished) => {
+                (PlayerState::Idle, vec![Effect::SaveSession])
+            }
+            (
+                PlayerState::Playing { track, .. } | PlayerState::Paused { track, .. },
+                PlayerEvent::Stop,
+            ) => (
+                PlayerState::Idle,
+                vec![Effect::StopTrack(track), Effect::SaveSession],
+            ),
+            (
+                PlayerState::Playing { track, .. } | PlayerState::Paused { track, .. },
+                PlayerEvent::Leave,
+            ) => (
+                PlayerState::Disconnected,
+                vec![
+                    Effect::StopTrack(track),
+                    Effect::LeaveVoice,
+                    Effect::SaveSession,
+                ],
+            ),
+            (_, PlayerEvent::Leave) => (
+                PlayerState::Disconnected,
+                vec![Effect::LeaveVoice, Effect::SaveSession],
+            ),
+            (state, event) => {
+                let invalid = InvalidTransition {
+                    state: state.name(),
+                    event: event.name(),
+                };
+                *self = state;
+                return Err(invalid);
+            }
+        };
+        *self = next;
+        Ok(effects)
+    }
+}
+
+impl<T> PlayerEvent<T> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlayerEvent::Join { .. } => "join",
+            PlayerEvent::Joined => "joined",
+            PlayerEvent::Load(_) => "load",
+            PlayerEvent::Started { .. } => "started",
+            PlayerEvent::Pause => "pause",
+            PlayerEvent::Resume => "resume",
+            PlayerEvent::Finished => "finished",
+            PlayerEvent::Failed => "failed",
+            PlayerEvent::Stop => "stop",
+            PlayerEvent::Leave => "leave",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_store::PlayArgs;
+
+    type State = PlayerState<u8>;
+    type Event = PlayerEvent<u8>;
+
+    const VOICE: ChannelId = ChannelId(2);
+    const TRACK: u8 = 7;
+
+    fn song(name: &str) -> QueuedSong {
+        QueuedSong {
+            channel_id: ChannelId(1),
+            name: name.to_string(),
+            play: PlayArgs::YoutubeLink(format!("https://youtu.be/{name}")),
+            requester: UserId(3),
+            duration: None,
+        }
+    }
+
+    fn connecting() -> State {
+        PlayerState::Connecting {
+            song: song("a"),
+            channel_id: VOICE,
+        }
+    }
+
+    fn buffering() -> State {
+        PlayerState::Buffering { song: song("a") }
+    }
+
+    fn playing() -> State {
+        PlayerState::Playing {
+            song: song("a"),
+            track: TRACK,
+        }
+    }
+
+    fn paused() -> State {
+        PlayerState::Paused {
+            song: song("a"),
+            track: TRACK,
+        }
+    }
+
+    fn all_states() -> Vec<State> {
+        vec![
+            PlayerState::Idle,
+            connecting(),
+            buffering(),
+            playing(),
+            paused(),
+            PlayerState::Disconnected,
+        ]
+    }
+
+    fn all_events() -> Vec<Event> {
+        vec![
+            PlayerEvent::Join {
+                song: song("b"),
+                channel_id: VOICE,
+            },
+            PlayerEvent::Joined,
+            PlayerEvent::Load(song("b")),
+            PlayerEvent::Started {
+                song: song("b"),
+                track: TRACK,
+            },
+            PlayerEvent::Pause,
+            PlayerEvent::Resume,
+            PlayerEvent::Finished,
+            PlayerEvent::Failed,
+            PlayerEvent::Stop,
+            PlayerEvent::Leave,
+        ]
+    }
+
+    fn assert_transition(from: State, event: Event, to: State, effects: Vec<Effect<u8>>) {
+        let mut state = from;
+        assert_eq!(state.apply(event), Ok(effects));
+        assert_eq!(state, to);
+    }
+
+    #[test]
+    fn join_from_idle() {
+        assert_transition(
+            PlayerState::Idle,
+            PlayerEvent::Join {
+                song: song("b"),
+                channel_id: VOICE,
+            },
+            PlayerState::Connecting {
+                song: song("b"),
+                channel_id: VOICE,
+            },
+            vec![Effect::JoinVoice(VOICE)],
+        );
+    }
+
+    #[test]
+    fn join_from_disconnected() {
+        assert_transition(
+            PlayerState::Disconnected,
+            PlayerEvent::Join {
+                song: song("b"),
+                channel_id: VOICE,
+            },
+            PlayerState::Connecting {
+                song: song("b"),
+                channel_id: VOICE,
+            },
+            vec![Effect::JoinVoice(VOICE)],
+        );
+    }
+
+    #[test]
+    fn load_from_idle() {
+        assert_transition(
+            PlayerState::Idle,
+            PlayerEvent::Load(song("b")),
+            PlayerState::Buffering { song: song("b") },
+            vec![Effect::ResolveAudio(song("b"))],
+        );
+    }
+
+    #[test]
+    fn joined_while_connecting() {
+        assert_transition(
+            connecting(),
+            PlayerEvent::Joined,
+            buffering(),
+            vec![Effect::ResolveAudio(song("a"))],
+        );
+    }
+
+    #[test]
+    fn failed_while_connecting() {
+        assert_transition(
+            connecting(),
+            PlayerEvent::Failed,
+            PlayerState::Disconnected,
+            vec![Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn started_while_buffering() {
+        // The song can be filled in from the audio, like the title of a search.
+        assert_transition(
+            buffering(),
+            PlayerEvent::Started {
+                song: song("b"),
+                track: TRACK,
+            },
+            PlayerState::Playing {
+                song: song("b"),
+                track: TRACK,
+            },
+            vec![Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn failed_while_buffering() {
+        assert_transition(
+            buffering(),
+            PlayerEvent::Failed,
+            PlayerState::Idle,
+            vec![Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn stop_while_buffering() {
+        assert_transition(
+            buffering(),
+            PlayerEvent::Stop,
+            PlayerState::Idle,
+            vec![Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn pause_while_playing() {
+        assert_transition(
+            playing(),
+            PlayerEvent::Pause,
+            paused(),
+            vec![Effect::PauseTrack(TRACK), Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn resume_while_paused() {
+        assert_transition(
+            paused(),
+            PlayerEvent::Resume,
+            playing(),
+            vec![Effect::ResumeTrack(TRACK), Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn finished_while_playing() {
+        assert_transition(
+            playing(),
+            PlayerEvent::Finished,
+            PlayerState::Idle,
+            vec![Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn finished_while_paused() {
+        assert_transition(
+            paused(),
+            PlayerEvent::Finished,
+            PlayerState::Idle,
+            vec![Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn stop_while_playing() {
+        assert_transition(
+            playing(),
+            PlayerEvent::Stop,
+            PlayerState::Idle,
+            vec![Effect::StopTrack(TRACK), Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn stop_while_paused() {
+        assert_transition(
+            paused(),
+            PlayerEvent::Stop,
+            PlayerState::Idle,
+            vec![Effect::StopTrack(TRACK), Effect::SaveSession],
+        );
+    }
+
+    #[test]
+    fn leave_stops_the_track() {
+        for from in [playing(), paused()] {
+            assert_transition(
+                from,
+                PlayerEvent::Leave,
+                PlayerState::Disconnected,
+                vec![
+                    Effect::StopTrack(TRACK),
+                    Effect::LeaveVoice,
+                    Effect::SaveSession,
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn leave_without_a_track() {
+        for from in [
+            PlayerState::Idle,
+            connecting(),
+            buffering(),
+            PlayerState::Disconnected,
+        ] {
+            assert_transition(
+                from,
+                PlayerEvent::Leave,
+                PlayerState::Disconnected,
+                vec![Effect::LeaveVoice, Effect::SaveSession],
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_transitions_leave_the_state_alone() {
+        let valid = [
+            ("idle", "join"),
+            ("idle", "load"),
+            ("disconnected", "join"),
+            ("connecting", "joined"),
+            ("connecting", "failed"),
+            ("buffering", "started"),
+            ("buffering", "failed"),
+            ("buffering", "stop"),
+            ("playing", "pause"),
+            ("playing", "finished"),
+            ("playing", "stop"),
+            ("paused", "resume"),
+            ("paused", "finished"),
+            ("paused", "stop"),
+        ];
+        for from in all_states() {
+            for event in all_events() {
+                let pair = (from.name(), event.name());
+                if valid.contains(&pair) || event == PlayerEvent::Leave {
+                    continue;
+                }
+                let mut state = from.clone();
+                assert_eq!(
+                    state.apply(event),
+                    Err(InvalidTransition {
+                        state: pair.0,
+                        event: pair.1,
+                    }),
+                    "{:?}",
+                    pair
+                );
+                assert_eq!(state, from);
+            }
+        }
+    }
+
+    #[test]
+    fn busy_while_a_song_is_playing_or_about_to() {
+        let busy: Vec<bool> = all_states().iter().map(State::is_busy).collect();
+        assert_eq!(busy, vec![false, true, true, true, true, false]);
+    }
+}
@@ -1,56 +1,113 @@
-///! Commented lines of code use the built-in discord-gateway instead of songbird.
+//! Plays music either locally through songbird or on a Lavalink node, picked
+//! with `MUSIC_BACKEND` (`local` or `lavalink`, the default).
 
 #[macro_use]
 extern crate tracing;
 
-use std::env;
+mod backend;
+mod commands;
+mod redis_store;
+mod search;
+mod util;
 
+use std::{collections::HashSet, env, sync::Arc, time::Duration};
+
+use backend::{LavalinkBackend, LocalBackend, MusicBackend, MusicBackendContainer};
+use commands::player::MusicState;
+use commands::{meta::*, music::*, owner::*};
+use redis_store::RedisStore;
+use search::SearchProvider;
 use serenity::{
     async_trait,
-    client::{Client, Context, EventHandler},
+    client::bridge::gateway::ShardManager,
     framework::{
         standard::{
-            macros::{command, group, hook},
-            Args, CommandResult,
+            macros::{group, hook},
+            CommandResult,
         },
         StandardFramework,
     },
     http::Http,
-    model::{channel::Message, gateway::Ready, id::GuildId, misc::Mentionable},
-    Result as SerenityResult,
+    model::{channel::Message, event::ResumedEvent, gateway::Ready, id::GuildId},
+    prelude::*,
 };
+use songbird::{SerenityInit, Songbird};
 
-use lavalink_rs::{gateway::*, model::*, LavalinkClient};
-use serenity::prelude::*;
-use songbird::SerenityInit;
+pub struct DiscordTokenContainer(pub String);
 
-struct Lavalink;
+impl TypeMapKey for DiscordTokenContainer {
+    type Value = String;
+}
 
-impl TypeMapKey for Lavalink {
-    type Value = LavalinkClient;
+impl DiscordTokenContainer {
+    pub fn get(&self) -> &str {
+        &self.0
+    }
 }
 
-struct Handler;
-struct LavalinkHandler;
+pub struct RedisClientContainer(pub redis::Client);
 
-#[async_trait]
-impl EventHandler for Handler {
-    async fn ready(&self, _: Context, ready: Ready) {
-        info!("{} is connected!", ready.user.name);
+impl TypeMapKey for RedisClientContainer {
+    type Value = redis::Client;
+}
+
+impl RedisClientContainer {
+    pub fn get(&self) -> &redis::Client {
+        &self.0
     }
+}
+
+/// How long saving the music sessions may take before shutting down anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct SearchProviderContainer;
 
-    async fn cache_ready(&self, _: Context, _guilds: Vec<GuildId>) {
-        info!("cache is ready!");
+impl TypeMapKey for SearchProviderContainer {
+    type Value = Arc<dyn SearchProvider>;
+}
+
+pub struct ShardManagerContainer;
+
+impl TypeMapKey for ShardManagerContainer {
+    type Value = Arc<Mutex<ShardManager>>;
+}
+
+/// Saves the music sessions so they can be restored on the next start, then
+/// stops the shards.
+pub async fn shutdown(data: Arc<RwLock<TypeMap>>, shard_manager: Arc<Mutex<ShardManager>>) {
+    let backend = data.read().await.get::<MusicBackendContainer>().cloned();
+    if let Some(backend) = backend {
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, backend.suspend())
+            .await
+            .is_err()
+        {
+            error!("Timed out saving the music sessions, shutting down anyway");
+        }
     }
+    shard_manager.lock().await.shutdown_all().await;
 }
 
+struct Handler;
+
 #[async_trait]
-impl LavalinkEventHandler for LavalinkHandler {
-    async fn track_start(&self, _client: LavalinkClient, event: TrackStart) {
-        info!("Track started!\nGuild: {}", event.guild_id);
+impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        info!("Connected as {}", ready.user.name);
+
+        let backend = ctx
+            .data
+            .read()
+            .await
+            .get::<MusicBackendContainer>()
+            .cloned();
+        if let Some(backend) = backend {
+            let guild_ids: Vec<GuildId> = ready.guilds.iter().map(|guild| guild.id()).collect();
+            backend.restore_sessions(&guild_ids).await;
+        }
     }
-    async fn track_finish(&self, _client: LavalinkClient, event: TrackFinish) {
-        info!("Track finished!\nGuild: {}", event.guild_id);
+
+    async fn resume(&self, _: Context, _: ResumedEvent) {
+        info!("Resumed");
     }
 }
 
@@ -64,311 +121,156 @@ async fn after(_ctx: &Context, _msg: &Message, command_name: &str, command_resul
     }
 }
 
+// TODO: Add help command
 #[group]
-#[only_in(guilds)]
-#[commands(join, leave, play, now_playing, skip, ping)]
+#[commands(
+    prefix,
+    ping,
+    status,
+    quit1,
+    cachestats,
+    joinchan,
+    pause,
+    play,
+    playnext,
+    search,
+    stop,
+    skip,
+    now_playing,
+    seek,
+    ff,
+    rewind,
+    volume,
+    quit,
+    unpause,
+    queue,
+    remove,
+    move_song,
+    swap,
+    clear,
+    shuffle,
+    fair,
+    loop_mode
+)]
 struct General;
 
 #[tokio::main]
-//#[tracing::instrument]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
     dotenv::dotenv().ok();
-    env::set_var("RUST_LOG", "info,lavalink_rs=debug");
+
     tracing_subscriber::fmt::init();
-    info!("Tracing initialized");
 
-    let token = env::var("DISCORD_TOKEN").expect("DISCORD_TOKEN");
-    let lavalink_port: u16 = env::var("LAVALINK_PORT").expect("LAVALINK_PORT").parse().unwrap();
-    let lavalink_host: String = env::var("LAVALINK_HOST").expect("LAVALINK_HOST");
+    let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
+    let redis_client = redis::Client::open(
+        env::var("REDIS_URL").expect("Expected a redis url in the environment"),
+    )
+    .expect("Failed to connect to Redis");
+    let use_lavalink = match env::var("MUSIC_BACKEND").as_deref() {
+        Ok("local") => false,
+        Ok("lavalink") | Err(_) => true,
+        Ok(other) => panic!(
+            "Unknown MUSIC_BACKEND {:?}, expected local or lavalink",
+            other
+        ),
+    };
 
     let http = Http::new_with_token(&token);
 
-    let bot_id = match http.get_current_application_info().await {
-        Ok(info) => info.id,
+    // We will fetch your bot's owners and id
+    let (owners, bot_id) = match http.get_current_application_info().await {
+        Ok(info) => {
+            let mut owners = HashSet::new();
+            owners.insert(info.owner.id);
+
+            (owners, info.id)
+        }
         Err(why) => panic!("Could not access application info: {:?}", why),
     };
 
+    // Create the framework
     let framework = StandardFramework::new()
-        .configure(|c| c.prefix("~"))
+        .configure(|c| {
+            c.owners(owners)
+                .dynamic_prefix(|ctx, msg| {
+                    Box::pin(async {
+                        match ctx
+                            .data
+                            .read()
+                            .await
+                            .get::<RedisClientContainer>()
+                            .expect("Failed to get RedisClientContainer")
+                            .get_async_connection()
+                            .await
+                        {
+                            Ok(conn) => match msg.guild_id {
+                                Some(guild_id) => {
+                                    let prefix = RedisStore::new(conn).get_prefix(guild_id).await;
+                                    match prefix {
+                                        Ok(prefix) => prefix,
+                                        Err(_) => None,
+                                    }
+                                }
+                                None => None,
+                            },
+                            Err(_) => None,
+                        }
+                    })
+                })
+                .prefix("~")
+        })
         .after(after)
         .group(&GENERAL_GROUP);
 
+    let manager = Songbird::serenity();
     let mut client = Client::builder(&token)
-        .event_handler(Handler)
         .framework(framework)
-        .register_songbird()
+        .event_handler(Handler)
+        .register_songbird_with(manager.clone())
         .await
         .expect("Err creating client");
 
-    let lava_client = LavalinkClient::builder(bot_id)
-        //let lava_client = LavalinkClient::builder(bot_id, &token)
-        .set_password(
-            env::var("LAVALINK_PASSWORD").expect("LAVALINK_PASSWORD"),
-        )
-        .set_is_ssl(false)
-        .set_host(lavalink_host)
-        .set_port(lavalink_port)
-        .build(LavalinkHandler)
-        .await?;
-
+    let search_provider = search::provider_from_env();
     {
         let mut data = client.data.write().await;
-        data.insert::<Lavalink>(lava_client);
-    }
-
-    let _ = client
-        .start()
-        .await
-        .map_err(|why| warn!("Client ended: {:?}", why));
-
-    Ok(())
-}
-
-#[command]
-async fn join(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).await.unwrap();
-    let guild_id = guild.id;
-
-    let channel_id = guild
-        .voice_states
-        .get(&msg.author.id)
-        .and_then(|voice_state| voice_state.channel_id);
-
-    let connect_to = match channel_id {
-        Some(channel) => channel,
-        None => {
-            check_msg(msg.reply(&ctx.http, "Join a voice channel first.").await);
-
-            return Ok(());
-        }
-    };
-
-    //let lava_client = {
-    //    let data = ctx.data.read().await;
-    //    data.get::<Lavalink>().unwrap().clone()
-    //};
-
-    //let raw_connection_info = lava_client.join(guild_id, connect_to).await;
-
-    //match raw_connection_info {
-    //    Ok(connection_info) => {
-    //        lava_client.create_session(&connection_info).await?;
-
-    //        check_msg(
-    //            msg.channel_id
-    //                .say(&ctx.http, &format!("Joined {}", connect_to.mention()))
-    //                .await,
-    //        );
-    //    }
-    //    Err(why) => check_msg(
-    //        msg.channel_id
-    //            .say(&ctx.http, format!("Error joining the channel: {}", why))
-    //            .await,
-    //    ),
-    //}
-
-    let manager = songbird::get(ctx).await.unwrap().clone();
-
-    let (_, handler) = manager.join_gateway(guild_id, connect_to).await;
-
-    match handler {
-        Ok(connection_info) => {
-            let data = ctx.data.read().await;
-            let lava_client = data.get::<Lavalink>().unwrap().clone();
-            lava_client.create_session(&connection_info).await?;
-
-            check_msg(
-                msg.channel_id
-                    .say(&ctx.http, &format!("Joined {}", connect_to.mention()))
-                    .await,
-            );
-        }
-        Err(why) => check_msg(
-            msg.channel_id
-                .say(&ctx.http, format!("Error joining the channel: {}", why))
-                .await,
-        ),
-    }
-
-    Ok(())
-}
-
-#[command]
-async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).await.unwrap();
-    let guild_id = guild.id;
-
-    //let lava_client = {
-    //    let data = ctx.data.read().await;
-    //    data.get::<Lavalink>().unwrap().clone()
-    //};
-
-    //lava_client.destroy(guild_id).await?;
-    //lava_client.leave(guild_id).await?;
-
-    let manager = songbird::get(ctx).await.unwrap().clone();
-    let has_handler = manager.get(guild_id).is_some();
-
-    if has_handler {
-        if let Err(e) = manager.remove(guild_id).await {
-            check_msg(
-                msg.channel_id
-                    .say(&ctx.http, format!("Failed: {:?}", e))
-                    .await,
-            );
-        }
-
-        {
-            let data = ctx.data.read().await;
-            let lava_client = data.get::<Lavalink>().unwrap().clone();
-            lava_client.destroy(guild_id).await?;
-        }
-
-        check_msg(msg.channel_id.say(&ctx.http, "Left voice channel").await);
-    } else {
-        check_msg(msg.reply(&ctx.http, "Not in a voice channel").await);
-    }
-
-    Ok(())
-}
-
-#[command]
-async fn ping(context: &Context, msg: &Message) -> CommandResult {
-    check_msg(msg.channel_id.say(&context.http, "Pong!").await);
-
-    Ok(())
-}
-
-#[command]
-#[min_args(1)]
-async fn play(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    let query = args.message().to_string();
-
-    let guild_id = match ctx.cache.guild_channel(msg.channel_id).await {
-        Some(channel) => channel.guild_id,
-        None => {
-            check_msg(
-                msg.channel_id
-                    .say(&ctx.http, "Error finding channel info")
-                    .await,
-            );
-
-            return Ok(());
-        }
-    };
-
-    let lava_client = {
-        let data = ctx.data.read().await;
-        data.get::<Lavalink>().unwrap().clone()
-    };
-
-    let manager = songbird::get(ctx).await.unwrap().clone();
-
-    if let Some(_handler) = manager.get(guild_id) {
-        //let connections = lava_client.discord_gateway_connections().await;
-        //if connections.contains_key(&guild_id.into()) {
-        let query_information = lava_client.auto_search_tracks(&query).await?;
-
-        if query_information.tracks.is_empty() {
-            check_msg(
-                msg.channel_id
-                    .say(&ctx, "Could not find any video of the search query.")
-                    .await,
-            );
-            return Ok(());
-        }
-
-        if let Err(why) = &lava_client
-            .play(guild_id, query_information.tracks[0].clone())
-            // Change this to play() if you want your own custom queue or no queue at all.
-            .queue()
+        data.insert::<ShardManagerContainer>(client.shard_manager.clone());
+        data.insert::<DiscordTokenContainer>(token);
+        data.insert::<RedisClientContainer>(redis_client.clone());
+        data.insert::<SearchProviderContainer>(search_provider.clone());
+
+        let backend: Arc<dyn MusicBackend> = if use_lavalink {
+            let backend = LavalinkBackend::from_env(
+                bot_id,
+                manager,
+                redis_client,
+                client.cache_and_http.http.clone(),
+            )
             .await
-        {
-            error!("{}", why);
-            return Ok(());
-        };
-        check_msg(
-            msg.channel_id
-                .say(
-                    &ctx.http,
-                    format!(
-                        "Added to queue: {}",
-                        query_information.tracks[0].info.as_ref().unwrap().title
-                    ),
-                )
-                .await,
-        );
-    } else {
-        check_msg(
-            msg.channel_id
-                .say(
-                    &ctx.http,
-                    "Use `~join` first, to connect the bot to your current voice channel.",
-                )
-                .await,
-        );
-    }
-
-    Ok(())
-}
-
-#[command]
-#[aliases(np)]
-async fn now_playing(ctx: &Context, msg: &Message) -> CommandResult {
-    let data = ctx.data.read().await;
-    let lava_client = data.get::<Lavalink>().unwrap().clone();
-
-    if let Some(node) = lava_client.nodes().await.get(&msg.guild_id.unwrap().0) {
-        if let Some(track) = &node.now_playing {
-            check_msg(
-                msg.channel_id
-                    .say(
-                        &ctx.http,
-                        format!("Now Playing: {}", track.track.info.as_ref().unwrap().title),
-                    )
-                    .await,
-            );
+            .expect("Failed to connect to Lavalink");
+            Arc::new(backend)
         } else {
-            check_msg(
-                msg.channel_id
-                    .say(&ctx.http, "Nothing is playing at the moment.")
-                    .await,
-            );
-        }
-    } else {
-        check_msg(
-            msg.channel_id
-                .say(&ctx.http, "Nothing is playing at the moment.")
-                .await,
-        );
+            let music_state = Arc::new(MusicState::from_env());
+            data.insert::<MusicState>(music_state.clone());
+            Arc::new(LocalBackend::new(
+                manager,
+                redis_client,
+                search_provider,
+                music_state,
+            ))
+        };
+        data.insert::<MusicBackendContainer>(backend);
     }
 
-    Ok(())
-}
-
-#[command]
-async fn skip(ctx: &Context, msg: &Message) -> CommandResult {
-    let data = ctx.data.read().await;
-    let lava_client = data.get::<Lavalink>().unwrap().clone();
-
-    if let Some(track) = lava_client.skip(msg.guild_id.unwrap()).await {
-        check_msg(
-            msg.channel_id
-                .say(
-                    ctx,
-                    format!("Skipped: {}", track.track.info.as_ref().unwrap().title),
-                )
-                .await,
-        );
-    } else {
-        check_msg(msg.channel_id.say(&ctx.http, "Nothing to skip.").await);
-    }
+    let shard_manager = client.shard_manager.clone();
+    let data = client.data.clone();
 
-    Ok(())
-}
+    tokio::spawn(async move {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Could not register ctrl+c handler");
+        shutdown(data, shard_manager).await;
+    });
 
-fn check_msg(result: SerenityResult<Message>) {
-    if let Err(why) = result {
-        error!("Error sending message: {:?}", why);
+    if let Err(why) = client.start().await {
+        error!("Client error: {:?}", why);
     }
 }
@@ -1,6 +1,11 @@
-use redis::{AsyncCommands, FromRedisValue, RedisError, ToRedisArgs};
-use serenity::model::id::{ChannelId, GuildId};
-use std::num::NonZeroUsize;
+use redis::{AsyncCommands, RedisError};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use std::time::Duration;
+
+/// How long resolved tracks stay cached by URL.
+const TRACK_CACHE_TTL: usize = 7 * 24 * 60 * 60;
+/// How long search queries stay cached. Shorter, since results change.
+const QUERY_CACHE_TTL: usize = 24 * 60 * 60;
 
 #[derive(Debug)]
 pub enum RedisStoreError {
@@ -15,7 +20,19 @@ impl From<RedisError> for RedisStoreError {
     }
 }
 
-#[derive(Debug, Clone)]
+impl std::fmt::Display for RedisStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RedisStoreError::RedisError(err) => write!(f, "Redis error: {}", err),
+            RedisStoreError::InvalidKey => write!(f, "Invalid key"),
+            RedisStoreError::Deserialization(why) => write!(f, "Deserialization error: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for RedisStoreError {}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum PlayArgs {
     SearchQuery(String),
     YoutubeLink(String),
@@ -49,46 +66,241 @@ impl PlayArgs {
     }
 }
 
-#[derive(Debug)]
+impl std::fmt::Display for PlayArgs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlayArgs::SearchQuery(query) => write!(f, "{}", query),
+            PlayArgs::YoutubeLink(link) => write!(f, "{}", link),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct QueuedSong {
-    channel_id: ChannelId,
-    name: String,
-    play: PlayArgs,
+    /// The text channel the song was requested from.
+    pub channel_id: ChannelId,
+    pub name: String,
+    pub play: PlayArgs,
+    pub requester: UserId,
+    pub duration: Option<Duration>,
 }
 
 impl QueuedSong {
     pub fn ser(&self) -> String {
         format!(
-            "{} :{} :{}",
+            "{} :{} :{} :{} :{}",
             self.channel_id.0,
+            self.requester.0,
+            self.duration
+                .map(|duration| duration.as_millis().to_string())
+                .unwrap_or_else(|| "-".to_string()),
             self.name.replace(':', "\\:"),
             self.play.ser()
         )
     }
 
     pub fn deser(s: &str) -> Result<QueuedSong, RedisStoreError> {
-        let args: Vec<&str> = s.splitn(3, " :").collect();
+        let args: Vec<&str> = s.splitn(5, " :").collect();
         match args.as_slice() {
-            [channel_id, name, play] => Ok(QueuedSong {
-                channel_id: ChannelId(channel_id.parse::<u64>().unwrap()),
+            [channel_id, requester, duration, name, play] => Ok(QueuedSong {
+                channel_id: ChannelId(parse_id(channel_id)?),
+                requester: UserId(parse_id(requester)?),
+                duration: match *duration {
+                    "-" => None,
+                    millis => Some(Duration::from_millis(millis.parse::<u64>().map_err(
+                        |_| {
+                            RedisStoreError::Deserialization(format!(
+                                "Invalid duration: {}",
+                                millis
+                            ))
+                        },
+                    )?)),
+                },
                 name: name.replace("\\:", ":"),
                 play: PlayArgs::deser(play)?,
             }),
             _ => Err(RedisStoreError::Deserialization(
-                "No channel_id found".to_string(),
+                "Missing queued song fields".to_string(),
+            )),
+        }
+    }
+}
+
+/// What is known about a track after resolving it, cached so the same song
+/// doesn't need youtube-dl every time.
+#[derive(Debug, Clone)]
+pub struct TrackMetadata {
+    pub title: String,
+    pub url: String,
+    pub duration: Option<Duration>,
+    pub thumbnail: Option<String>,
+    pub uploader: Option<String>,
+}
+
+impl TrackMetadata {
+    pub fn ser(&self) -> String {
+        format!(
+            "{} :{} :{} :{} :{}",
+            self.duration
+                .map(|duration| duration.as_millis().to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            self.url.replace(':', "\\:"),
+            self.thumbnail.as_deref().unwrap_or("").replace(':', "\\:"),
+            self.uploader.as_deref().unwrap_or("").replace(':', "\\:"),
+            self.title.replace(':', "\\:")
+        )
+    }
+
+    pub fn deser(s: &str) -> Result<TrackMetadata, RedisStoreError> {
+        let args: Vec<&str> = s.splitn(5, " :").collect();
+        let optional = |s: &str| Some(s.replace("\\:", ":")).filter(|s| !s.is_empty());
+        match args.as_slice() {
+            [duration, url, thumbnail, uploader, title] => Ok(TrackMetadata {
+                duration: match *duration {
+                    "-" => None,
+                    millis => Some(Duration::from_millis(millis.parse::<u64>().map_err(
+                        |_| {
+                            RedisStoreError::Deserialization(format!(
+                                "Invalid duration: {}",
+                                millis
+                            ))
+                        },
+                    )?)),
+                },
+                url: url.replace("\\:", ":"),
+                thumbnail: optional(thumbnail),
+                uploader: optional(uploader),
+                title: title.replace("\\:", ":"),
+            }),
+            _ => Err(RedisStoreError::Deserialization(
+                "Missing track fields".to_string(),
             )),
         }
     }
 }
 
+/// What the songbird bot was playing in a guild, so it can pick up where it
+/// left off after a restart. The queue itself is stored separately.
+#[derive(Debug, Clone)]
+pub struct GuildSession {
+    pub voice_channel_id: ChannelId,
+    /// The text channel the session is bound to, for announcements.
+    pub text_channel_id: ChannelId,
+    pub position: Duration,
+    pub paused: bool,
+    pub song: QueuedSong,
+}
+
+impl GuildSession {
+    pub fn ser(&self) -> String {
+        format!(
+            "{} :{} :{} :{} :{}",
+            self.voice_channel_id.0,
+            self.text_channel_id.0,
+            self.position.as_millis(),
+            if self.paused { "paused" } else { "playing" },
+            self.song.ser()
+        )
+    }
+
+    pub fn deser(s: &str) -> Result<GuildSession, RedisStoreError> {
+        let args: Vec<&str> = s.splitn(5, " :").collect();
+        match args.as_slice() {
+            [voice_channel_id, text_channel_id, position, paused, song] => Ok(GuildSession {
+                voice_channel_id: ChannelId(parse_id(voice_channel_id)?),
+                text_channel_id: ChannelId(parse_id(text_channel_id)?),
+                position: Duration::from_millis(position.parse::<u64>().map_err(|_| {
+                    RedisStoreError::Deserialization(format!("Invalid position: {}", position))
+                })?),
+                paused: *paused == "paused",
+                song: QueuedSong::deser(song)?,
+            }),
+            _ => Err(RedisStoreError::Deserialization(
+                "Missing session fields".to_string(),
+            )),
+        }
+    }
+}
+
+fn parse_id(s: &str) -> Result<u64, RedisStoreError> {
+    s.parse::<u64>()
+        .map_err(|_| RedisStoreError::Deserialization(format!("Invalid id: {}", s)))
+}
+
 fn prefix_key(guild_id: GuildId) -> String {
     format!("prefix:{}", guild_id.0)
 }
 
+fn volume_key(guild_id: GuildId) -> String {
+    format!("volume:{}", guild_id.0)
+}
+
+fn loop_mode_key(guild_id: GuildId) -> String {
+    format!("loop_mode:{}", guild_id.0)
+}
+
+fn fair_scheduling_key(guild_id: GuildId) -> String {
+    format!("fair_scheduling:{}", guild_id.0)
+}
+
 fn queue_key(guild_id: GuildId) -> String {
     format!("queue:{}", guild_id.0)
 }
 
+fn session_key(guild_id: GuildId) -> String {
+    format!("session:{}", guild_id.0)
+}
+
+fn track_key(url: &str) -> String {
+    format!("track:{}", canonical_url(url))
+}
+
+fn query_key(query: &str) -> String {
+    format!("query:{}", normalize_query(query))
+}
+
+fn search_key(query: &str) -> String {
+    format!("search:{}", normalize_query(query))
+}
+
+const TRACK_CACHE_HITS_KEY: &str = "track_cache:hits";
+const TRACK_CACHE_MISSES_KEY: &str = "track_cache:misses";
+
+/// Reduces the different forms of a YouTube video link to one, so they share
+/// a cache entry. Other links are kept as they are.
+fn canonical_url(url: &str) -> String {
+    let url = url.trim();
+    let rest = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let rest = rest
+        .strip_prefix("www.")
+        .or_else(|| rest.strip_prefix("m."))
+        .or_else(|| rest.strip_prefix("music."))
+        .unwrap_or(rest);
+    let id = if let Some(path) = rest.strip_prefix("youtu.be/") {
+        path.split(|c| c == '?' || c == '#').next()
+    } else if let Some(query) = rest.strip_prefix("youtube.com/watch?") {
+        query
+            .split(|c| c == '&' || c == '#')
+            .find_map(|param| param.strip_prefix("v="))
+    } else {
+        None
+    };
+    match id.filter(|id| !id.is_empty()) {
+        Some(id) => format!("https://www.youtube.com/watch?v={}", id),
+        None => url.to_string(),
+    }
+}
+
+fn normalize_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
 pub struct RedisStore {
     conn: redis::aio::Connection,
 }
@@ -119,6 +331,66 @@ impl RedisStore {
             .map_err(RedisStoreError::RedisError)
     }
 
+    pub async fn get_volume(&mut self, guild_id: GuildId) -> Result<Option<u16>, RedisStoreError> {
+        self.conn
+            .get(volume_key(guild_id))
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    pub async fn set_volume(
+        &mut self,
+        guild_id: GuildId,
+        volume: u16,
+    ) -> Result<(), RedisStoreError> {
+        self.conn
+            .set(volume_key(guild_id), volume)
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    pub async fn get_loop_mode(
+        &mut self,
+        guild_id: GuildId,
+    ) -> Result<Option<String>, RedisStoreError> {
+        self.conn
+            .get(loop_mode_key(guild_id))
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    pub async fn set_loop_mode(
+        &mut self,
+        guild_id: GuildId,
+        loop_mode: &str,
+    ) -> Result<(), RedisStoreError> {
+        self.conn
+            .set(loop_mode_key(guild_id), loop_mode)
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    pub async fn get_fair_scheduling(
+        &mut self,
+        guild_id: GuildId,
+    ) -> Result<Option<bool>, RedisStoreError> {
+        self.conn
+            .get(fair_scheduling_key(guild_id))
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    pub async fn set_fair_scheduling(
+        &mut self,
+        guild_id: GuildId,
+        enabled: bool,
+    ) -> Result<(), RedisStoreError> {
+        self.conn
+            .set(fair_scheduling_key(guild_id), enabled)
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
     pub async fn get_queue(
         &mut self,
         guild_id: GuildId,
@@ -135,6 +407,45 @@ impl RedisStore {
             .transpose()
     }
 
+    pub async fn queue_len(&mut self, guild_id: GuildId) -> Result<usize, RedisStoreError> {
+        self.conn
+            .llen(queue_key(guild_id))
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    /// Looks at the song at the front of the queue without taking it off.
+    pub async fn peek_queue(
+        &mut self,
+        guild_id: GuildId,
+    ) -> Result<Option<QueuedSong>, RedisStoreError> {
+        self.conn
+            .lindex::<_, Option<String>>(queue_key(guild_id), 0)
+            .await
+            .map_err(RedisStoreError::RedisError)?
+            .as_deref()
+            .map(QueuedSong::deser)
+            .transpose()
+    }
+
+    /// Replaces the whole queue in one transaction.
+    pub async fn set_queue<'a>(
+        &mut self,
+        guild_id: GuildId,
+        songs: impl IntoIterator<Item = &'a QueuedSong>,
+    ) -> Result<(), RedisStoreError> {
+        let key = queue_key(guild_id);
+        let songs: Vec<String> = songs.into_iter().map(QueuedSong::ser).collect();
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&key).ignore();
+        if !songs.is_empty() {
+            pipe.rpush(&key, songs).ignore();
+        }
+        pipe.query_async(&mut self.conn)
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
     pub async fn pop_queue(
         &mut self,
         guild_id: GuildId,
@@ -148,14 +459,205 @@ impl RedisStore {
             .transpose()
     }
 
+    /// Takes up to `count` songs off the front of the queue in one
+    /// transaction.
+    pub async fn take_queue(
+        &mut self,
+        guild_id: GuildId,
+        count: usize,
+    ) -> Result<Vec<QueuedSong>, RedisStoreError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let key = queue_key(guild_id);
+        let (songs,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .lrange(&key, 0, count as isize - 1)
+            .ltrim(&key, count as isize, -1)
+            .ignore()
+            .query_async(&mut self.conn)
+            .await
+            .map_err(RedisStoreError::RedisError)?;
+        songs.iter().map(|s| QueuedSong::deser(s)).collect()
+    }
+
+    /// Adds a song to the back of the queue, returning the new queue length.
     pub async fn push_queue(
         &mut self,
         guild_id: GuildId,
         song: QueuedSong,
-    ) -> Result<(), RedisStoreError> {
+    ) -> Result<usize, RedisStoreError> {
         self.conn
             .rpush(queue_key(guild_id), song.ser())
             .await
             .map_err(RedisStoreError::RedisError)
     }
+
+    /// Adds a song to the front of the queue, returning the new queue length.
+    pub async fn push_queue_front(
+        &mut self,
+        guild_id: GuildId,
+        song: QueuedSong,
+    ) -> Result<usize, RedisStoreError> {
+        self.conn
+            .lpush(queue_key(guild_id), song.ser())
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    pub async fn get_session(
+        &mut self,
+        guild_id: GuildId,
+    ) -> Result<Option<GuildSession>, RedisStoreError> {
+        self.conn
+            .get::<_, Option<String>>(session_key(guild_id))
+            .await
+            .map_err(RedisStoreError::RedisError)?
+            .as_deref()
+            .map(GuildSession::deser)
+            .transpose()
+    }
+
+    pub async fn set_session(
+        &mut self,
+        guild_id: GuildId,
+        session: &GuildSession,
+    ) -> Result<(), RedisStoreError> {
+        self.conn
+            .set(session_key(guild_id), session.ser())
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    pub async fn remove_session(&mut self, guild_id: GuildId) -> Result<(), RedisStoreError> {
+        self.conn
+            .del(session_key(guild_id))
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    pub async fn get_cached_track(
+        &mut self,
+        url: &str,
+    ) -> Result<Option<TrackMetadata>, RedisStoreError> {
+        let key = track_key(url);
+        self.get_cached(&key).await
+    }
+
+    pub async fn get_cached_query(
+        &mut self,
+        query: &str,
+    ) -> Result<Option<TrackMetadata>, RedisStoreError> {
+        let key = query_key(query);
+        self.get_cached(&key).await
+    }
+
+    async fn get_cached(&mut self, key: &str) -> Result<Option<TrackMetadata>, RedisStoreError> {
+        let track = self
+            .conn
+            .get::<_, Option<String>>(key)
+            .await
+            .map_err(RedisStoreError::RedisError)?
+            .as_deref()
+            .map(TrackMetadata::deser)
+            .transpose()?;
+        self.count_cache_lookup(track.is_some()).await?;
+        Ok(track)
+    }
+
+    /// Caches a resolved track by its URL, and by the query that found it if
+    /// there was one.
+    pub async fn cache_track(
+        &mut self,
+        query: Option<&str>,
+        track: &TrackMetadata,
+    ) -> Result<(), RedisStoreError> {
+        let mut pipe = redis::pipe();
+        pipe.set_ex(track_key(&track.url), track.ser(), TRACK_CACHE_TTL)
+            .ignore();
+        if let Some(query) = query {
+            pipe.set_ex(query_key(query), track.ser(), QUERY_CACHE_TTL)
+                .ignore();
+        }
+        pipe.query_async(&mut self.conn)
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    pub async fn get_cached_search(
+        &mut self,
+        query: &str,
+    ) -> Result<Option<Vec<TrackMetadata>>, RedisStoreError> {
+        let results = self
+            .conn
+            .lrange::<_, Option<Vec<String>>>(search_key(query), 0, -1)
+            .await
+            .map_err(RedisStoreError::RedisError)?
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                v.iter()
+                    .map(|s| TrackMetadata::deser(s))
+                    .collect::<Result<Vec<TrackMetadata>, RedisStoreError>>()
+            })
+            .transpose()?;
+        self.count_cache_lookup(results.is_some()).await?;
+        Ok(results)
+    }
+
+    /// Caches the results of a search, along with each track by its URL so
+    /// picking one of them doesn't need another lookup.
+    pub async fn cache_search(
+        &mut self,
+        query: &str,
+        results: &[TrackMetadata],
+    ) -> Result<(), RedisStoreError> {
+        if results.is_empty() {
+            return Ok(());
+        }
+        let key = search_key(query);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .del(&key)
+            .ignore()
+            .rpush(
+                &key,
+                results
+                    .iter()
+                    .map(TrackMetadata::ser)
+                    .collect::<Vec<String>>(),
+            )
+            .ignore()
+            .expire(&key, QUERY_CACHE_TTL)
+            .ignore();
+        for track in results {
+            pipe.set_ex(track_key(&track.url), track.ser(), TRACK_CACHE_TTL)
+                .ignore();
+        }
+        pipe.query_async(&mut self.conn)
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    async fn count_cache_lookup(&mut self, hit: bool) -> Result<(), RedisStoreError> {
+        let key = if hit {
+            TRACK_CACHE_HITS_KEY
+        } else {
+            TRACK_CACHE_MISSES_KEY
+        };
+        self.conn
+            .incr(key, 1)
+            .await
+            .map_err(RedisStoreError::RedisError)
+    }
+
+    /// Returns how many track cache lookups hit and missed.
+    pub async fn track_cache_stats(&mut self) -> Result<(u64, u64), RedisStoreError> {
+        let (hits, misses): (Option<u64>, Option<u64>) = redis::pipe()
+            .get(TRACK_CACHE_HITS_KEY)
+            .get(TRACK_CACHE_MISSES_KEY)
+            .query_async(&mut self.conn)
+            .await
+            .map_err(RedisStoreError::RedisError)?;
+        Ok((hits.unwrap_or_default(), misses.unwrap_or_default()))
+    }
 }
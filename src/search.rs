@@ -0,0 +1,597 @@
+use crate::redis_store::TrackMetadata;
+use serde_json::Value;
+use serenity::async_trait;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+const YOUTUBE_DL_COMMAND: &str = "youtube-dl";
+const YOUTUBE_API_BASE_URL: &str = "https://www.googleapis.com/youtube/v3";
+/// How long to stick to the fallback once the API quota runs out. The quota
+/// resets daily, but there is no telling exactly when from the response.
+const QUOTA_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub enum SearchError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Http(reqwest::Error),
+    Api(String),
+    QuotaExceeded,
+    Ytdl(String),
+    NotFound,
+}
+
+impl From<std::io::Error> for SearchError {
+    fn from(err: std::io::Error) -> Self {
+        SearchError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SearchError {
+    fn from(err: serde_json::Error) -> Self {
+        SearchError::Json(err)
+    }
+}
+
+impl From<reqwest::Error> for SearchError {
+    fn from(err: reqwest::Error) -> Self {
+        SearchError::Http(err)
+    }
+}
+
+impl std::fmt::Display for SearchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchError::Io(err) => write!(f, "Failed to run {}: {}", YOUTUBE_DL_COMMAND, err),
+            SearchError::Json(err) => write!(f, "Unexpected search output: {}", err),
+            SearchError::Http(err) => write!(f, "Failed to reach the YouTube API: {}", err),
+            SearchError::Api(message) => write!(f, "YouTube API error: {}", message),
+            SearchError::QuotaExceeded => write!(f, "YouTube API quota exceeded"),
+            SearchError::Ytdl(why) => write!(f, "{} failed: {}", YOUTUBE_DL_COMMAND, why),
+            SearchError::NotFound => write!(f, "Nothing found"),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+/// Somewhere to look songs up without starting to play them.
+#[async_trait]
+pub trait SearchProvider: Send + Sync {
+    /// Finds the top `limit` videos for a query.
+    async fn search(&self, query: &str, limit: usize) -> Result<Vec<TrackMetadata>, SearchError>;
+}
+
+/// Picks the search provider from the environment: the YouTube Data API when
+/// `YT_API_KEY` is set, backed by youtube-dl for when its quota runs out, or
+/// only youtube-dl otherwise. `YT_API_BASE_URL` points the API client
+/// somewhere else, such as a local stand-in for testing.
+pub fn provider_from_env() -> Arc<dyn SearchProvider> {
+    match std::env::var("YT_API_KEY") {
+        Ok(api_key) if !api_key.is_empty() => {
+            let base_url = std::env::var("YT_API_BASE_URL")
+                .unwrap_or_else(|_| YOUTUBE_API_BASE_URL.to_string());
+            Arc::new(FallbackSearch::new(
+                Box::new(YoutubeApiSearch::new(api_key, base_url)),
+                Box::new(YtdlSearch),
+            ))
+        }
+        _ => Arc::new(YtdlSearch),
+    }
+}
+
+/// Searches through youtube-dl. Slow, since it resolves every result, but
+/// needs no API key.
+pub struct YtdlSearch;
+
+#[async_trait]
+impl SearchProvider for YtdlSearch {
+    async fn search(&self, query: &str, limit: usize) -> Result<Vec<TrackMetadata>, SearchError> {
+        let output = Command::new(YOUTUBE_DL_COMMAND)
+            .args([
+                "-j",
+                "--ignore-config",
+                "--no-warnings",
+                &format!("ytsearch{}:{}", limit, query),
+            ])
+            .stdin(Stdio::null())
+            .output()
+            .await?;
+
+        let mut results = Vec::new();
+        for line in output.stdout.split(|b| *b == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            let value: Value = serde_json::from_slice(line)?;
+            results.extend(track_from_ytdl_output(&value));
+        }
+
+        Ok(results)
+    }
+}
+
+/// Looks up a single link through youtube-dl, without downloading it.
+pub async fn ytdl_metadata(url: &str) -> Result<TrackMetadata, SearchError> {
+    let output = Command::new(YOUTUBE_DL_COMMAND)
+        .args([
+            "-j",
+            "--no-playlist",
+            "--ignore-config",
+            "--no-warnings",
+            url,
+        ])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(SearchError::Ytdl(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    let line = output
+        .stdout
+        .split(|b| *b == b'\n')
+        .next()
+        .unwrap_or_default();
+    let value: Value = serde_json::from_slice(line)?;
+    track_from_ytdl_output(&value).ok_or(SearchError::NotFound)
+}
+
+fn track_from_ytdl_output(value: &Value) -> Option<TrackMetadata> {
+    Some(TrackMetadata {
+        title: value.get("title")?.as_str()?.to_string(),
+        url: value.get("webpage_url")?.as_str()?.to_string(),
+        duration: value
+            .get("duration")
+            .and_then(Value::as_f64)
+            .map(Duration::from_secs_f64),
+        thumbnail: value
+            .get("thumbnail")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        uploader: value
+            .get("uploader")
+            .or_else(|| value.get("channel"))
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    })
+}
+
+/// Searches through the YouTube Data API, which takes one request for the
+/// results and one more for their durations.
+pub struct YoutubeApiSearch {
+    http: reqwest::Client,
+    api_key: String,
+    base_url: String,
+}
+
+impl YoutubeApiSearch {
+    pub fn new(api_key: String, base_url: String) -> Self {
+        YoutubeApiSearch {
+            http: reqwest::Client::new(),
+            api_key,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    async fn get(&self, endpoint: &str, query: &[(&str, &str)]) -> Result<Value, SearchError> {
+        let response = self
+            .http
+            .get(format!("{}/{}", self.base_url, endpoint))
+            .query(query)
+            .query(&[("key", self.api_key.as_str())])
+            .send()
+            .await?;
+        let status = response.status();
+        let body: Value = serde_json::from_slice(&response.bytes().await?)?;
+        if status.is_success() {
+            return Ok(body);
+        }
+
+        let error = &body["error"];
+        let quota_exceeded = error["errors"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e["reason"].as_str())
+            .any(|reason| reason == "quotaExceeded" || reason == "dailyLimitExceeded");
+        if quota_exceeded {
+            Err(SearchError::QuotaExceeded)
+        } else {
+            Err(SearchError::Api(
+                error["message"]
+                    .as_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| status.to_string()),
+            ))
+        }
+    }
+}
+
+#[async_trait]
+impl SearchProvider for YoutubeApiSearch {
+    async fn search(&self, query: &str, limit: usize) -> Result<Vec<TrackMetadata>, SearchError> {
+        let limit = limit.to_string();
+        let results = self
+            .get(
+                "search",
+                &[
+                    ("part", "snippet"),
+                    ("type", "video"),
+                    ("maxResults", &limit),
+                    ("q", query),
+                ],
+            )
+            .await?;
+        let items = results["items"].as_array().cloned().unwrap_or_default();
+        let ids: Vec<&str> = items
+            .iter()
+            .filter_map(|item| item["id"]["videoId"].as_str())
+            .collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let videos = self
+            .get(
+                "videos",
+                &[("part", "contentDetails"), ("id", &ids.join(","))],
+            )
+            .await?;
+        let durations: Vec<(&str, Duration)> = videos["items"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|video| {
+                let duration = video["contentDetails"]["duration"].as_str()?;
+                Some((video["id"].as_str()?, parse_iso8601_duration(duration)?))
+            })
+            .collect();
+
+        Ok(items
+            .iter()
+            .filter_map(|item| {
+                let id = item["id"]["videoId"].as_str()?;
+                let snippet = &item["snippet"];
+                let thumbnails = &snippet["thumbnails"];
+                Some(TrackMetadata {
+                    title: unescape_html(snippet["title"].as_str()?),
+                    url: format!("https://www.youtube.com/watch?v={}", id),
+                    // Live streams report a zero duration.
+                    duration: durations
+                        .iter()
+                        .find(|(video_id, _)| *video_id == id)
+                        .map(|(_, duration)| *duration)
+                        .filter(|duration| !duration.is_zero()),
+                    thumbnail: ["high", "medium", "default"]
+                        .iter()
+                        .find_map(|size| thumbnails[*size]["url"].as_str())
+                        .map(str::to_string),
+                    uploader: snippet["channelTitle"].as_str().map(unescape_html),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Uses one provider, switching to another for a while once the first runs
+/// out of quota.
+pub struct FallbackSearch {
+    primary: Box<dyn SearchProvider>,
+    fallback: Box<dyn SearchProvider>,
+    primary_paused_until: Mutex<Option<Instant>>,
+}
+
+impl FallbackSearch {
+    pub fn new(primary: Box<dyn SearchProvider>, fallback: Box<dyn SearchProvider>) -> Self {
+        FallbackSearch {
+            primary,
+            fallback,
+            primary_paused_until: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl SearchProvider for FallbackSearch {
+    async fn search(&self, query: &str, limit: usize) -> Result<Vec<TrackMetadata>, SearchError> {
+        let paused = self
+            .primary_paused_until
+            .lock()
+            .expect("Search provider lock poisoned")
+            .map_or(false, |until| Instant::now() < until);
+        if !paused {
+            match self.primary.search(query, limit).await {
+                Err(SearchError::QuotaExceeded) => {
+                    eprintln!("Search quota exceeded, falling back for a while");
+                    *self
+                        .primary_paused_until
+                        .lock()
+                        .expect("Search provider lock poisoned") =
+                        Some(Instant::now() + QUOTA_RETRY_AFTER);
+                }
+                result => return result,
+            }
+        }
+        self.fallback.search(query, limit).await
+    }
+}
+
+/// Parses the ISO 8601 durations the API uses, like `PT1H2M3S`.
+fn parse_iso8601_duration(s: &str) -> Option<Duration> {
+    let s = s.strip_prefix('P')?;
+    let mut secs = 0;
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in s.chars() {
+        match c {
+            'T' => in_time = true,
+            '0'..='9' => number.push(c),
+            unit => {
+                let value: u64 = std::mem::take(&mut number).parse().ok()?;
+                secs += value
+                    * match (unit, in_time) {
+                        ('W', false) => 7 * 24 * 60 * 60,
+                        ('D', false) => 24 * 60 * 60,
+                        ('H', true) => 60 * 60,
+                        ('M', true) => 60,
+                        ('S', true) => 1,
+                        _ => return None,
+                    };
+            }
+        }
+    }
+    Some(Duration::from_secs(secs))
+}
+
+/// The API returns titles HTML escaped.
+fn unescape_html(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A local stand-in for the YouTube API, answering each path prefix with
+    /// a canned status and body. Counts the requests it gets.
+    async fn serve(routes: Vec<(&'static str, u16, Value)>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = vec![0; 8192];
+                let read = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..read]);
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let (status, body) = routes
+                    .iter()
+                    .find(|(prefix, ..)| path.starts_with(prefix) && path.contains("key=test-key"))
+                    .map(|(_, status, body)| (*status, body.to_string()))
+                    .unwrap_or((404, "{}".to_string()));
+                let response = format!(
+                    "HTTP/1.1 {} Canned\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (base_url, requests)
+    }
+
+    fn api(base_url: &str) -> YoutubeApiSearch {
+        YoutubeApiSearch::new("test-key".to_string(), format!("{}/", base_url))
+    }
+
+    fn track(title: &str) -> TrackMetadata {
+        TrackMetadata {
+            title: title.to_string(),
+            url: format!("https://youtu.be/{}", title),
+            duration: None,
+            thumbnail: None,
+            uploader: None,
+        }
+    }
+
+    /// Stands in for youtube-dl behind `FallbackSearch`.
+    struct FixedSearch(TrackMetadata);
+
+    #[async_trait]
+    impl SearchProvider for FixedSearch {
+        async fn search(&self, _: &str, _: usize) -> Result<Vec<TrackMetadata>, SearchError> {
+            Ok(vec![self.0.clone()])
+        }
+    }
+
+    fn quota_exceeded() -> Value {
+        json!({
+            "error": {
+                "code": 403,
+                "message": "The request cannot be completed because you have exceeded your quota.",
+                "errors": [{ "reason": "quotaExceeded" }]
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn api_search_returns_results_with_durations() {
+        let (base_url, requests) = serve(vec![
+            (
+                "/search",
+                200,
+                json!({
+                    "items": [
+                        {
+                            "id": { "videoId": "abc" },
+                            "snippet": {
+                                "title": "Tom &amp; Jerry &quot;Theme&quot;",
+                                "channelTitle": "Cartoons",
+                                "thumbnails": {
+                                    "default": { "url": "https://i.ytimg.com/abc/default.jpg" },
+                                    "high": { "url": "https://i.ytimg.com/abc/high.jpg" }
+                                }
+                            }
+                        },
+                        {
+                            "id": { "videoId": "live" },
+                            "snippet": { "title": "Radio", "thumbnails": {} }
+                        }
+                    ]
+                }),
+            ),
+            (
+                "/videos",
+                200,
+                json!({
+                    "items": [
+                        { "id": "abc", "contentDetails": { "duration": "PT3M33S" } },
+                        { "id": "live", "contentDetails": { "duration": "P0D" } }
+                    ]
+                }),
+            ),
+        ])
+        .await;
+
+        let results = api(&base_url).search("tom and jerry", 2).await.unwrap();
+
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].title, "Tom & Jerry \"Theme\"");
+        assert_eq!(results[0].url, "https://www.youtube.com/watch?v=abc");
+        assert_eq!(results[0].duration, Some(Duration::from_secs(213)));
+        assert_eq!(
+            results[0].thumbnail.as_deref(),
+            Some("https://i.ytimg.com/abc/high.jpg")
+        );
+        assert_eq!(results[0].uploader.as_deref(), Some("Cartoons"));
+        assert_eq!(results[1].title, "Radio");
+        assert_eq!(results[1].duration, None);
+        assert_eq!(results[1].thumbnail, None);
+    }
+
+    #[tokio::test]
+    async fn api_search_without_results_skips_durations() {
+        let (base_url, requests) = serve(vec![("/search", 200, json!({ "items": [] }))]).await;
+
+        let results = api(&base_url).search("nothing", 5).await.unwrap();
+
+        assert!(results.is_empty());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn api_errors_carry_their_message() {
+        let (base_url, _) = serve(vec![(
+            "/search",
+            400,
+            json!({ "error": { "message": "Bad query", "errors": [{ "reason": "badRequest" }] } }),
+        )])
+        .await;
+
+        match api(&base_url).search("query", 1).await {
+            Err(SearchError::Api(message)) => assert_eq!(message, "Bad query"),
+            other => panic!("Expected an API error, got {:?}", other.map(|r| r.len())),
+        }
+    }
+
+    #[tokio::test]
+    async fn api_reports_exceeded_quota() {
+        let (base_url, _) = serve(vec![("/search", 403, quota_exceeded())]).await;
+
+        assert!(matches!(
+            api(&base_url).search("query", 1).await,
+            Err(SearchError::QuotaExceeded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn fallback_takes_over_once_the_quota_runs_out() {
+        let (base_url, requests) = serve(vec![("/search", 403, quota_exceeded())]).await;
+        let search = FallbackSearch::new(
+            Box::new(api(&base_url)),
+            Box::new(FixedSearch(track("fallback"))),
+        );
+
+        let first = search.search("query", 1).await.unwrap();
+        let second = search.search("query", 1).await.unwrap();
+
+        assert_eq!(first[0].title, "fallback");
+        assert_eq!(second[0].title, "fallback");
+        // The API isn't asked again until the quota should have reset.
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fallback_stays_out_of_the_way_while_the_api_works() {
+        let (base_url, _) = serve(vec![("/search", 200, json!({ "items": [] }))]).await;
+        let search = FallbackSearch::new(
+            Box::new(api(&base_url)),
+            Box::new(FixedSearch(track("fallback"))),
+        );
+
+        assert!(search.search("query", 1).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_iso8601_durations() {
+        assert_eq!(
+            parse_iso8601_duration("PT1H2M3S"),
+            Some(Duration::from_secs(3723))
+        );
+        assert_eq!(
+            parse_iso8601_duration("PT45S"),
+            Some(Duration::from_secs(45))
+        );
+        assert_eq!(
+            parse_iso8601_duration("PT10M"),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            parse_iso8601_duration("P1DT1S"),
+            Some(Duration::from_secs(86401))
+        );
+        assert_eq!(
+            parse_iso8601_duration("P1W"),
+            Some(Duration::from_secs(604800))
+        );
+        assert_eq!(parse_iso8601_duration("P0D"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn rejects_malformed_iso8601_durations() {
+        assert_eq!(parse_iso8601_duration("1H2M"), None);
+        assert_eq!(parse_iso8601_duration("PT1X"), None);
+        // Minutes before the time designator would be months.
+        assert_eq!(parse_iso8601_duration("P1M"), None);
+        assert_eq!(parse_iso8601_duration("PTS"), None);
+    }
+
+    #[test]
+    fn unescapes_html_entities() {
+        assert_eq!(
+            unescape_html("Tom &amp; Jerry &quot;Live&quot; &#39;99 &lt;3&gt;"),
+            "Tom & Jerry \"Live\" '99 <3>"
+        );
+        // Ampersands go last, so escaped entities stay entities.
+        assert_eq!(unescape_html("&amp;quot;"), "&quot;");
+        assert_eq!(unescape_html("Plain title"), "Plain title");
+    }
+}
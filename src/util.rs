@@ -1 +1,198 @@
+use std::time::Duration;
 
+use crate::redis_store::QueuedSong;
+
+const PROGRESS_BAR_WIDTH: usize = 20;
+
+/// Formats a duration as `m:ss`, or `h:mm:ss` once it reaches an hour.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+/// Formats a wait for estimates, rounded to the minute, like `~12 min`.
+pub fn format_eta(duration: Duration) -> String {
+    let minutes = (duration.as_secs() + 30) / 60;
+    match minutes {
+        0 => "under a minute".to_string(),
+        1..=59 => format!("~{minutes} min"),
+        _ => format!("~{}h {:02}min", minutes / 60, minutes % 60),
+    }
+}
+
+/// Draws a text progress bar, like `▬▬▬▬🔘▬▬▬▬▬`, with the knob at how far
+/// `elapsed` is into `total`.
+pub fn progress_bar(elapsed: Duration, total: Duration) -> String {
+    let filled = if total.is_zero() {
+        0
+    } else {
+        (elapsed.as_secs_f64() / total.as_secs_f64() * PROGRESS_BAR_WIDTH as f64) as usize
+    };
+    let knob = filled.min(PROGRESS_BAR_WIDTH - 1);
+    (0..PROGRESS_BAR_WIDTH)
+        .map(|i| if i == knob { '🔘' } else { '▬' })
+        .collect()
+}
+
+/// Parses a human timestamp or offset, such as `1:23`, `1:02:03`, `90`,
+/// `30s` or `1m30s`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+
+    if s.contains(':') {
+        let parts = s
+            .split(':')
+            .map(|part| part.parse::<u64>().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        if parts.len() > 3 {
+            return None;
+        }
+        let secs = parts
+            .iter()
+            .try_fold(0u64, |acc, &part| acc.checked_mul(60)?.checked_add(part))?;
+        return Some(Duration::from_secs(secs));
+    }
+
+    let mut secs: u64 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        let part = number.parse::<u64>().ok()?.checked_mul(unit)?;
+        secs = secs.checked_add(part)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        secs = secs.checked_add(number.parse::<u64>().ok()?)?;
+    }
+
+    Some(Duration::from_secs(secs))
+}
+
+/// Estimates how long until the song at `position` in `queue` starts, given
+/// the song playing, if any, and how far into it playback is. Also tells
+/// whether any song before it has an unknown duration.
+pub fn time_until<'a>(
+    current: Option<&QueuedSong>,
+    elapsed: Duration,
+    queue: impl IntoIterator<Item = &'a QueuedSong>,
+    position: usize,
+) -> (Duration, bool) {
+    let mut wait = Duration::ZERO;
+    let mut unknown_durations = false;
+    if let Some(song) = current {
+        match song.duration {
+            Some(duration) => wait += duration.saturating_sub(elapsed),
+            None => unknown_durations = true,
+        }
+    }
+    for song in queue.into_iter().take(position) {
+        match song.duration {
+            Some(duration) => wait += duration,
+            None => unknown_durations = true,
+        }
+    }
+    (wait, unknown_durations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis_store::PlayArgs;
+    use serenity::model::id::{ChannelId, UserId};
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_duration("1:23"), Some(Duration::from_secs(83)));
+        assert_eq!(parse_duration("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_duration(" 0:05 "), Some(Duration::from_secs(5)));
+        assert_eq!(parse_duration("1:2:3:4"), None);
+        assert_eq!(parse_duration("1:xx"), None);
+    }
+
+    #[test]
+    fn parses_offsets() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1m30s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1m30"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5x"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn rejects_overflowing_input() {
+        assert_eq!(parse_duration("5124095576030432h"), None);
+        assert_eq!(parse_duration("999999999999999999:0"), None);
+        assert_eq!(parse_duration("18446744073709551615s1s"), None);
+        assert_eq!(parse_duration("18446744073709551616"), None);
+        assert_eq!(
+            parse_duration("18446744073709551615"),
+            Some(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    fn song(duration: Option<u64>) -> QueuedSong {
+        QueuedSong {
+            channel_id: ChannelId(1),
+            name: "song".to_string(),
+            play: PlayArgs::YoutubeLink("https://youtu.be/id".to_string()),
+            requester: UserId(2),
+            duration: duration.map(Duration::from_secs),
+        }
+    }
+
+    #[test]
+    fn estimates_start_times() {
+        let current = song(Some(200));
+        let queue = [song(Some(60)), song(Some(30)), song(Some(90))];
+        let elapsed = Duration::from_secs(50);
+        assert_eq!(
+            time_until(Some(&current), elapsed, &queue, 0),
+            (Duration::from_secs(150), false)
+        );
+        assert_eq!(
+            time_until(Some(&current), elapsed, &queue, 2),
+            (Duration::from_secs(240), false)
+        );
+        assert_eq!(
+            time_until(None, Duration::ZERO, &queue, 3),
+            (Duration::from_secs(180), false)
+        );
+    }
+
+    #[test]
+    fn flags_unknown_durations_before_the_song() {
+        let current = song(None);
+        let queue = [song(Some(60)), song(None), song(Some(90))];
+        assert_eq!(
+            time_until(Some(&current), Duration::ZERO, &queue, 1),
+            (Duration::from_secs(60), true)
+        );
+        assert_eq!(
+            time_until(None, Duration::ZERO, &queue, 1),
+            (Duration::from_secs(60), false)
+        );
+        assert_eq!(
+            time_until(None, Duration::ZERO, &queue, 3),
+            (Duration::from_secs(150), true)
+        );
+    }
+}